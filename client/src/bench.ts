@@ -4,9 +4,12 @@ import { makeId } from "./utils";
 async function main() {
   const config = { projectId: "bench" };
   const admin = new AdminClient(config);
-  await admin.mutate(`
-      CREATE TABLE IF NOT EXISTS person (id TEXT PRIMARY KEY, name TEXT NOT NULL)
-  `);
+  await admin.migrate([
+    {
+      name: "001_person",
+      sql: "CREATE TABLE person (id TEXT PRIMARY KEY, name TEXT NOT NULL)",
+    },
+  ]);
   await admin.setPolicy({
     queries: [
       {
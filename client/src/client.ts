@@ -5,30 +5,63 @@ interface ClientConfig {
   readonly projectId: string;
   readonly databaseId?: string;
 }
+interface AdminClientConfig extends ClientConfig {
+  readonly adminKey?: string;
+}
 export class AdminClient {
   private client: Got;
   constructor({
     address = "http://localhost:9000",
     projectId,
     databaseId = "default",
-  }: ClientConfig) {
+    adminKey = "admin",
+  }: AdminClientConfig) {
     this.client = got.extend({
       prefixUrl: `${address}/v0/${projectId}/${databaseId}`,
-      headers: { authorization: "Bearer admin" },
+      headers: { authorization: `Bearer ${adminKey}` },
       throwHttpErrors: false,
       allowGetBody: true,
     });
   }
 
-  async setPolicy(policy: Policy): Promise<void> {
-    const response = await this.client.put(`policy`, { json: policy });
+  // Returns the policy's new version. With `ifVersion`, the policy is only replaced if
+  // that's still its version.
+  async setPolicy(policy: Policy, ifVersion?: number): Promise<PolicyVersion> {
+    const response = await this.client.put(`policy`, {
+      json: policy,
+      headers: ifVersion === undefined ? {} : { "if-match": `"${ifVersion}"` },
+    });
+    if (response.statusCode !== 200) {
+      throw new ApiError(response.statusCode, response.body);
+    }
+    return JSON.parse(response.body);
+  }
+
+  // Applies the migrations that haven't been yet, returning their names. The ones that
+  // have must come first, unchanged.
+  async migrate(
+    migrations: Migration[],
+    dryRun = false
+  ): Promise<MigrationReport> {
+    const response = await this.client.post(`migrations`, {
+      json: migrations,
+      searchParams: dryRun ? { dryRun: "true" } : {},
+    });
     if (response.statusCode !== 200) {
       throw new ApiError(response.statusCode, response.body);
     }
     return JSON.parse(response.body);
   }
 
-  async mutate(rawSql: string): Promise<void> {
+  async fetchSchema(): Promise<Schema> {
+    const response = await this.client.get(`schema`);
+    if (response.statusCode !== 200) {
+      throw new ApiError(response.statusCode, response.body);
+    }
+    return JSON.parse(response.body);
+  }
+
+  async mutate(rawSql: string): Promise<MutationResult> {
     const response = await this.client.post(`raw`, { body: rawSql });
     if (response.statusCode !== 200) {
       throw new ApiError(response.statusCode, response.body);
@@ -45,16 +78,26 @@ export class AdminClient {
   }
 }
 
+interface EndUserClientConfig extends ClientConfig {
+  // A JWT identifying the end user, signed with the project's configured key.
+  readonly token?: string;
+  readonly valueEncoding?: "plain" | "typed";
+}
 export class Client {
   private client: Got;
   constructor({
     address = "http://localhost:9000",
     projectId,
     databaseId = "default",
-  }: ClientConfig) {
+    token,
+    valueEncoding = "plain",
+  }: EndUserClientConfig) {
     this.client = got.extend({
       prefixUrl: `${address}/v0/${projectId}/${databaseId}`,
-      headers: { authorization: "Bearer admin" },
+      headers: {
+        "ezdb-value-encoding": valueEncoding,
+        ...(token ? { authorization: `Bearer ${token}` } : {}),
+      },
       throwHttpErrors: false,
       allowGetBody: true,
     });
@@ -70,7 +113,27 @@ export class Client {
     return JSON.parse(response.body);
   }
 
-  async mutate(name: string, params: Values): Promise<void> {
+  // Fetches one page of a query's results. Pass the `nextPageToken` of each page
+  // to get the one after it.
+  async queryPage(
+    name: string,
+    params: Values,
+    page: PageRequest
+  ): Promise<QueryPage> {
+    const searchParams: { [key: string]: string | number } = {};
+    if (page.limit !== undefined) searchParams.limit = page.limit;
+    if (page.pageToken !== undefined) searchParams.pageToken = page.pageToken;
+    const response = await this.client.get(`named/${name}`, {
+      json: params,
+      searchParams,
+    });
+    if (response.statusCode !== 200) {
+      throw new ApiError(response.statusCode, response.body);
+    }
+    return JSON.parse(response.body);
+  }
+
+  async mutate(name: string, params: Values): Promise<MutationResult> {
     const response = await this.client.post(`named/${name}`, {
       json: params,
     });
@@ -79,9 +142,48 @@ export class Client {
     }
     return JSON.parse(response.body);
   }
+
+  // Runs every step in one transaction. If any step fails, none of them take effect.
+  async batch(steps: BatchStep[]): Promise<(Values[] | MutationResult)[]> {
+    const response = await this.client.post(`batch`, { json: steps });
+    if (response.statusCode !== 200) {
+      throw new ApiError(response.statusCode, response.body);
+    }
+    return JSON.parse(response.body);
+  }
+}
+
+export interface BatchStep {
+  readonly type: "query" | "mutation";
+  readonly name: string;
+  readonly params?: Values;
 }
 
-export type Value = number | string;
+export interface PageRequest {
+  readonly limit?: number;
+  readonly pageToken?: string;
+}
+
+export interface QueryPage {
+  readonly rows: Values[];
+  // Missing from the last page.
+  readonly nextPageToken?: string;
+}
+
+export interface MutationResult {
+  readonly changes: number;
+  readonly lastInsertRowid: number;
+  // The rows produced by a `RETURNING` clause, if there is one.
+  readonly rows: Values[];
+}
+
+// With `valueEncoding: "typed"`, blobs, integers beyond 2^53 and infinite reals come
+// back as (and may be sent as) tagged objects.
+export type TypedValue =
+  | { readonly $blob: string }
+  | { readonly $int: string }
+  | { readonly $real: string };
+export type Value = number | string | TypedValue;
 export type Values = { [key: string]: Value };
 
 export interface Policy {
@@ -89,13 +191,46 @@ export interface Policy {
   readonly mutations: MutationPolicy[];
 }
 
+export interface Migration {
+  readonly name: string;
+  readonly sql: string;
+}
+
+export interface MigrationReport {
+  readonly applied: string[];
+  readonly dryRun: boolean;
+}
+
+export interface PolicyVersion {
+  readonly version: number;
+}
+
 export interface QueryPolicy {
   readonly name: string;
   readonly rawSql: string;
+  readonly rule?: string;
+  readonly timeoutMs?: number;
 }
 export interface MutationPolicy {
   readonly name: string;
   readonly rawSql: string;
+  readonly rule?: string;
+  readonly timeoutMs?: number;
+}
+
+export interface Schema {
+  readonly queries: TemplateSignature[];
+  readonly mutations: TemplateSignature[];
+}
+
+export interface TemplateSignature {
+  readonly name: string;
+  readonly params: string[];
+  readonly columns: ColumnSignature[];
+}
+export interface ColumnSignature {
+  readonly name: string;
+  readonly declType: string | null;
 }
 
 class ApiError extends Error {
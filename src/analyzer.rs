@@ -1,6 +1,330 @@
+use crate::core::Policy;
+use rusqlite::{Batch, Connection, Statement};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+const AUTH_PARAM_PREFIX: &str = ":auth.";
+const SQLITE_AUTH_PARAM_PREFIX: &str = ":auth::";
+
+/// Returns true if `name` is one of the params reserved for the caller's claims.
+pub fn is_auth_param(name: &str) -> bool {
+    let unprefixed = name.trim_start_matches([':', '@', '$']);
+    unprefixed.starts_with("auth.") || unprefixed.starts_with("auth::")
+}
+
+/// SQLite doesn't allow `.` in parameter names, so templates refer to `:auth.uid` but
+/// it is actually bound as `:auth::uid`. This maps the SQLite name back to the one the
+/// template uses.
+pub fn template_param_name(name: &str) -> Cow<'_, str> {
+    match name.strip_prefix(SQLITE_AUTH_PARAM_PREFIX) {
+        Some(claim) => Cow::Owned(format!("{}{}", AUTH_PARAM_PREFIX, claim)),
+        None => Cow::Borrowed(name),
+    }
+}
+
+/// Rewrites every `:auth.<claim>` param in `sql` to the `:auth::<claim>` form that
+/// SQLite understands, leaving string literals, quoted identifiers and comments alone.
+pub fn rewrite_auth_params(sql: &str) -> String {
+    let bytes = sql.as_bytes();
+    let mut out = String::with_capacity(sql.len());
+    let mut last = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' | b'"' | b'`' => i = skip_until(bytes, i + 1, &[bytes[i]]),
+            b'[' => i = skip_until(bytes, i + 1, b"]"),
+            b'-' if bytes.get(i + 1) == Some(&b'-') => i = skip_until(bytes, i + 2, b"\n"),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => i = skip_until(bytes, i + 2, b"*/"),
+            b':' if sql[i..].starts_with(AUTH_PARAM_PREFIX)
+                && bytes
+                    .get(i + AUTH_PARAM_PREFIX.len())
+                    .is_some_and(|b| b.is_ascii_alphanumeric() || *b == b'_') =>
+            {
+                out.push_str(&sql[last..i]);
+                out.push_str(SQLITE_AUTH_PARAM_PREFIX);
+                i += AUTH_PARAM_PREFIX.len();
+                last = i;
+            }
+            _ => i += 1,
+        }
+    }
+    out.push_str(&sql[last..]);
+    out
+}
+
+/// Rewrites every `IN (:param)` list whose param is bound to a JSON array so that it
+/// matches any element of the array, i.e. `IN (SELECT value FROM json_each(:param))`.
+pub fn expand_array_params(sql: &str, arrays: &HashSet<&str>) -> String {
+    let bytes = sql.as_bytes();
+    let mut out = String::with_capacity(sql.len());
+    let mut last = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' | b'"' | b'`' => i = skip_until(bytes, i + 1, &[bytes[i]]),
+            b'[' => i = skip_until(bytes, i + 1, b"]"),
+            b'-' if bytes.get(i + 1) == Some(&b'-') => i = skip_until(bytes, i + 2, b"\n"),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => i = skip_until(bytes, i + 2, b"*/"),
+            b'(' if follows_in_keyword(&sql[..i]) => match in_list_param(&sql[i + 1..]) {
+                Some((param, len)) if arrays.contains(param) => {
+                    out.push_str(&sql[last..i]);
+                    out.push_str("(SELECT value FROM json_each(");
+                    out.push_str(param);
+                    out.push_str("))");
+                    i += 1 + len;
+                    last = i;
+                }
+                _ => i += 1,
+            },
+            _ => i += 1,
+        }
+    }
+    out.push_str(&sql[last..]);
+    out
+}
+
+fn follows_in_keyword(before: &str) -> bool {
+    let before = before.trim_end().as_bytes();
+    before.len() >= 2
+        && before[before.len() - 2..].eq_ignore_ascii_case(b"in")
+        && !before
+            .get(before.len().wrapping_sub(3))
+            .is_some_and(|b| b.is_ascii_alphanumeric() || *b == b'_')
+}
+
+// Matches a list consisting of a single named param, like ` :ids )`. Returns the param
+// and the length of the list, including the closing paren.
+fn in_list_param(list: &str) -> Option<(&str, usize)> {
+    let start = list.len() - list.trim_start().len();
+    let rest = &list[start..];
+    if !rest.starts_with([':', '@', '$']) {
+        return None;
+    }
+    let len = 1 + rest[1..]
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(rest.len() - 1);
+    if len == 1 {
+        return None;
+    }
+    let after = &rest[len..];
+    let close = after.len() - after.trim_start().len();
+    if !after[close..].starts_with(')') {
+        return None;
+    }
+    Some((&rest[..len], start + len + close + 1))
+}
+
+/// Whether `sql` orders its results, with an `ORDER BY` that isn't inside parens (like
+/// a subquery's or a window's). Only then can a query's pages line up.
+pub fn has_order_by(sql: &str) -> bool {
+    let bytes = sql.as_bytes();
+    let mut depth = 0usize;
+    let mut last_word: Option<&str> = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' | b'"' | b'`' => {
+                i = skip_until(bytes, i + 1, &[bytes[i]]);
+                last_word = None;
+            }
+            b'[' => {
+                i = skip_until(bytes, i + 1, b"]");
+                last_word = None;
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => i = skip_until(bytes, i + 2, b"\n"),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => i = skip_until(bytes, i + 2, b"*/"),
+            b if b.is_ascii_alphanumeric() || b == b'_' => {
+                let start = i;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                let word = &sql[start..i];
+                if depth == 0
+                    && word.eq_ignore_ascii_case("by")
+                    && last_word.is_some_and(|w| w.eq_ignore_ascii_case("order"))
+                {
+                    return true;
+                }
+                last_word = Some(word);
+            }
+            b if b.is_ascii_whitespace() => i += 1,
+            b => {
+                match b {
+                    b'(' => depth += 1,
+                    b')' => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                last_word = None;
+                i += 1;
+            }
+        }
+    }
+    false
+}
+
+/// A problem with one of the templates in a policy.
+#[derive(Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TemplateError {
+    pub kind: String,
+    pub name: String,
+    pub message: String,
+}
+
+/// Prepares every template in `policy` against the current schema of `conn`, and checks
+/// that each one is a single statement and that queries don't modify the database.
+/// Returns every problem found, not just the first.
+pub fn validate_policy(conn: &Connection, policy: &Policy) -> Result<(), Vec<TemplateError>> {
+    let mut errors = Vec::new();
+    let templates = policy
+        .queries
+        .iter()
+        .map(|q| ("query", &q.name, &q.raw_sql, q.timeout_ms))
+        .chain(
+            policy
+                .mutations
+                .iter()
+                .map(|m| ("mutation", &m.name, &m.raw_sql, m.timeout_ms)),
+        );
+    let mut seen = HashSet::new();
+    for (kind, name, raw_sql, timeout_ms) in templates {
+        let problem = if !seen.insert((kind, name)) {
+            Some(format!("duplicate {} name", kind))
+        } else if timeout_ms == Some(0) {
+            Some("timeoutMs must be positive".to_owned())
+        } else {
+            match prepare_template(conn, raw_sql) {
+                Err(message) => Some(message),
+                Ok(ref stmt) if kind == "query" && !stmt.readonly() => {
+                    Some("queries must not modify the database".to_owned())
+                }
+                Ok(_) => None,
+            }
+        };
+        if let Some(message) = problem {
+            errors.push(TemplateError {
+                kind: kind.to_owned(),
+                name: name.to_owned(),
+                message,
+            });
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// The inferred signatures of every template in a policy.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Schema {
+    pub queries: Vec<TemplateSignature>,
+    pub mutations: Vec<TemplateSignature>,
+}
+
+/// What a template expects and returns: its bind params, and the columns of each row
+/// it produces (mutations only produce rows if they use `RETURNING`).
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TemplateSignature {
+    pub name: String,
+    pub params: Vec<String>,
+    pub columns: Vec<ColumnSignature>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ColumnSignature {
+    pub name: String,
+    /// The declared type of the column, if the column comes directly from a table.
+    pub decl_type: Option<String>,
+}
+
+/// Infers the signature of every template in `policy` by preparing it against `conn`.
+pub fn describe_policy(conn: &Connection, policy: &Policy) -> rusqlite::Result<Schema> {
+    let describe = |name: &str, raw_sql: &str| -> rusqlite::Result<TemplateSignature> {
+        let stmt = conn.prepare(&rewrite_auth_params(raw_sql))?;
+        Ok(describe_statement(name, &stmt))
+    };
+    Ok(Schema {
+        queries: policy
+            .queries
+            .iter()
+            .map(|q| describe(&q.name, &q.raw_sql))
+            .collect::<Result<_, _>>()?,
+        mutations: policy
+            .mutations
+            .iter()
+            .map(|m| describe(&m.name, &m.raw_sql))
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+fn describe_statement(name: &str, stmt: &Statement) -> TemplateSignature {
+    // SQLite numbers params from 1. Anonymous params like `?` have no name.
+    let params = (1..=stmt.parameter_count())
+        .map(|i| match stmt.parameter_name(i) {
+            Some(param) => template_param_name(param).into_owned(),
+            None => format!("?{}", i),
+        })
+        .collect();
+    let columns = stmt
+        .columns()
+        .into_iter()
+        .map(|col| ColumnSignature {
+            name: col.name().to_owned(),
+            decl_type: col.decl_type().map(str::to_owned),
+        })
+        .collect();
+    TemplateSignature {
+        name: name.to_owned(),
+        params,
+        columns,
+    }
+}
+
+/// Prepares a template, which must consist of exactly one statement.
+pub fn prepare_template<'conn>(
+    conn: &'conn Connection,
+    raw_sql: &str,
+) -> Result<Statement<'conn>, String> {
+    let sql = rewrite_auth_params(raw_sql);
+    let mut batch = Batch::new(conn, &sql);
+    let stmt = match batch.next() {
+        Ok(Some(stmt)) => stmt,
+        Ok(None) => return Err("template is empty".to_owned()),
+        Err(e) => return Err(describe_error(e)),
+    };
+    match batch.next() {
+        Ok(None) => Ok(stmt),
+        Ok(Some(_)) | Err(_) => Err("templates must be a single statement".to_owned()),
+    }
+}
+
+fn describe_error(err: rusqlite::Error) -> String {
+    match err {
+        rusqlite::Error::SqliteFailure(_, Some(msg)) => msg,
+        err => err.to_string(),
+    }
+}
+
+// Returns the index just past the next occurrence of `terminator`, or the end of input.
+fn skip_until(bytes: &[u8], start: usize, terminator: &[u8]) -> usize {
+    bytes[start..]
+        .windows(terminator.len())
+        .position(|w| w == terminator)
+        .map_or(bytes.len(), |pos| start + pos + terminator.len())
+}
+
 #[cfg(test)]
 mod test {
-    use rusqlite::{Connection, NO_PARAMS};
+    use super::{
+        describe_policy, expand_array_params, has_order_by, rewrite_auth_params, validate_policy,
+        TemplateError,
+    };
+    use crate::core::{MutationPolicy, Policy, QueryPolicy};
+    use rusqlite::Connection;
 
     #[test]
     fn smoke_test() {
@@ -13,7 +337,7 @@ mod test {
                 my_float REAL
             )
         "#,
-            NO_PARAMS,
+            [],
         )
         .unwrap();
         let stmt = conn
@@ -21,4 +345,132 @@ mod test {
             .unwrap();
         assert_eq!(stmt.column_names(), vec!["my_int", "my_string", "my_float"]);
     }
+
+    #[test]
+    fn auth_params_are_rewritten_outside_of_literals() {
+        assert_eq!(
+            rewrite_auth_params("SELECT * FROM notes WHERE owner = :auth.uid AND id = :id"),
+            "SELECT * FROM notes WHERE owner = :auth::uid AND id = :id"
+        );
+        assert_eq!(
+            rewrite_auth_params("SELECT ':auth.uid', \":auth.uid\" -- :auth.uid\n, :auth.role"),
+            "SELECT ':auth.uid', \":auth.uid\" -- :auth.uid\n, :auth::role"
+        );
+    }
+
+    #[test]
+    fn in_lists_of_arrays_are_expanded() {
+        let arrays = vec![":ids"].into_iter().collect();
+        assert_eq!(
+            expand_array_params(
+                "SELECT * FROM t WHERE id IN ( :ids ) AND x IN (:x)",
+                &arrays
+            ),
+            "SELECT * FROM t WHERE id IN (SELECT value FROM json_each(:ids)) AND x IN (:x)"
+        );
+        assert_eq!(
+            expand_array_params(
+                "SELECT min(:ids), 'in (:ids)' FROM t WHERE id in(:ids)",
+                &arrays
+            ),
+            "SELECT min(:ids), 'in (:ids)' FROM t WHERE id in(SELECT value FROM json_each(:ids))"
+        );
+    }
+
+    #[test]
+    fn only_top_level_order_bys_count() {
+        assert!(has_order_by("SELECT id FROM person ORDER BY id"));
+        assert!(has_order_by(
+            "SELECT id FROM person order\n  -- by name\n by id"
+        ));
+        assert!(!has_order_by("SELECT id FROM person"));
+        assert!(!has_order_by(
+            "SELECT * FROM (SELECT id FROM person ORDER BY id) WHERE name = 'ORDER BY'"
+        ));
+        assert!(!has_order_by(
+            "SELECT row_number() OVER (ORDER BY id) AS n FROM person"
+        ));
+        assert!(!has_order_by("SELECT \"order\" by_id FROM person"));
+    }
+
+    #[test]
+    fn invalid_templates_are_all_reported() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE person (id TEXT, name TEXT)", [])
+            .unwrap();
+        let query = |name: &str, raw_sql: &str| QueryPolicy {
+            name: name.to_owned(),
+            raw_sql: raw_sql.to_owned(),
+            rule: Default::default(),
+            timeout_ms: None,
+        };
+        let mutation = |name: &str, raw_sql: &str| MutationPolicy {
+            name: name.to_owned(),
+            raw_sql: raw_sql.to_owned(),
+            rule: Default::default(),
+            timeout_ms: None,
+        };
+        let policy = Policy {
+            queries: vec![
+                query("ok", "SELECT name FROM person WHERE id = :auth.uid"),
+                query("typo", "SELEC name FROM person"),
+                query("missing", "SELECT * FROM people"),
+                query("writes", "DELETE FROM person"),
+            ],
+            mutations: vec![
+                mutation("ok", "INSERT INTO person (id, name) VALUES (:id, :name)"),
+                mutation("two", "DELETE FROM person; DROP TABLE person"),
+            ],
+        };
+        let errors = validate_policy(&conn, &policy).unwrap_err();
+        let failed: Vec<(&str, &str)> = errors
+            .iter()
+            .map(|TemplateError { kind, name, .. }| (kind.as_str(), name.as_str()))
+            .collect();
+        assert_eq!(
+            failed,
+            vec![
+                ("query", "typo"),
+                ("query", "missing"),
+                ("query", "writes"),
+                ("mutation", "two"),
+            ]
+        );
+    }
+
+    #[test]
+    fn signatures_are_inferred_from_templates() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE person (id TEXT, name TEXT, age INTEGER)", [])
+            .unwrap();
+        let policy = Policy {
+            queries: vec![QueryPolicy {
+                name: "get".to_owned(),
+                raw_sql: "SELECT name, age + 1 AS next_age FROM person WHERE id = :id AND id != :auth.uid"
+                    .to_owned(),
+                rule: Default::default(),
+                timeout_ms: None,
+            }],
+            mutations: vec![MutationPolicy {
+                name: "add".to_owned(),
+                raw_sql: "INSERT INTO person (id, name) VALUES (:id, :name)".to_owned(),
+                rule: Default::default(),
+                timeout_ms: None,
+            }],
+        };
+        let schema = describe_policy(&conn, &policy).unwrap();
+
+        let get = &schema.queries[0];
+        assert_eq!(get.params, vec![":id", ":auth.uid"]);
+        let columns: Vec<(&str, Option<&str>)> = get
+            .columns
+            .iter()
+            .map(|c| (c.name.as_str(), c.decl_type.as_deref()))
+            .collect();
+        assert_eq!(columns, vec![("name", Some("TEXT")), ("next_age", None)]);
+
+        let add = &schema.mutations[0];
+        assert_eq!(add.params, vec![":id", ":name"]);
+        assert!(add.columns.is_empty());
+    }
 }
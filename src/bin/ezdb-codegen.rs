@@ -0,0 +1,121 @@
+use ezdb::analyzer::Schema;
+use ezdb::core::Policy;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[actix_rt::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init();
+    let opts: CliOptions = CliOptions::from_args();
+    if opts.typescript.is_none() && opts.rust.is_none() {
+        return Err(invalid_input(
+            "nothing to do: pass --typescript and/or --rust",
+        ));
+    }
+
+    let schema = match (opts.policy, opts.server) {
+        (Some(policy), None) => schema_from_files(&policy, opts.schema.as_deref())?,
+        (None, Some(server)) => {
+            let admin_key = opts
+                .admin_key
+                .ok_or_else(|| invalid_input("--server requires --admin-key"))?;
+            schema_from_server(&server, &opts.project, &opts.database, &admin_key).await?
+        }
+        _ => return Err(invalid_input("pass exactly one of --policy or --server")),
+    };
+
+    if let Some(path) = opts.typescript {
+        std::fs::write(path, ezdb::codegen::typescript(&schema))?;
+    }
+    if let Some(dir) = opts.rust {
+        for (name, contents) in ezdb::codegen::rust_crate(&schema, &opts.crate_name) {
+            let path = dir.join(name);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, contents)?;
+        }
+    }
+    Ok(())
+}
+
+// Prepares the policy's templates against an in-memory database built from the DDL.
+fn schema_from_files(policy: &PathBuf, ddl: Option<&std::path::Path>) -> std::io::Result<Schema> {
+    let policy: Policy = serde_json::from_str(&std::fs::read_to_string(policy)?)?;
+    let conn = rusqlite::Connection::open_in_memory().map_err(std::io::Error::other)?;
+    if let Some(ddl) = ddl {
+        conn.execute_batch(&std::fs::read_to_string(ddl)?)
+            .map_err(std::io::Error::other)?;
+    }
+    if let Err(errors) = ezdb::analyzer::validate_policy(&conn, &policy) {
+        for e in &errors {
+            eprintln!("{} {}: {}", e.kind, e.name, e.message);
+        }
+        return Err(invalid_input("policy does not match the schema"));
+    }
+    ezdb::analyzer::describe_policy(&conn, &policy).map_err(std::io::Error::other)
+}
+
+async fn schema_from_server(
+    server: &str,
+    project: &str,
+    database: &str,
+    admin_key: &str,
+) -> std::io::Result<Schema> {
+    let url = format!(
+        "{}/v0/{}/{}/schema",
+        server.trim_end_matches('/'),
+        project,
+        database
+    );
+    let mut resp = actix_web::client::Client::new()
+        .get(&url)
+        .bearer_auth(admin_key)
+        .send()
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let body = resp
+        .body()
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    if !resp.status().is_success() {
+        return Err(std::io::Error::other(format!(
+            "{} returned {}: {}",
+            url,
+            resp.status(),
+            String::from_utf8_lossy(&body)
+        )));
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+fn invalid_input(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+#[derive(StructOpt, Debug)]
+struct CliOptions {
+    /// A policy file, like `sample-data/policy.json`.
+    #[structopt(long, parse(from_os_str))]
+    policy: Option<PathBuf>,
+    /// SQL that creates the tables the policy refers to. Only used with --policy.
+    #[structopt(long, parse(from_os_str))]
+    schema: Option<PathBuf>,
+    /// Address of a running server to fetch the template signatures from.
+    #[structopt(long)]
+    server: Option<String>,
+    #[structopt(long, default_value = "default")]
+    project: String,
+    #[structopt(long, default_value = "default")]
+    database: String,
+    #[structopt(long, env = "EZDB_ADMIN_KEY")]
+    admin_key: Option<String>,
+    /// Where to write the TypeScript client.
+    #[structopt(long, parse(from_os_str))]
+    typescript: Option<PathBuf>,
+    /// Directory to write the Rust client crate into.
+    #[structopt(long, parse(from_os_str))]
+    rust: Option<PathBuf>,
+    #[structopt(long, default_value = "ezdb-client")]
+    crate_name: String,
+}
@@ -1,6 +1,8 @@
 use actix::Actor;
 use actix_web::{middleware, App, HttpServer};
+use log::warn;
 use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
 #[actix_rt::main]
@@ -9,15 +11,54 @@ async fn main() -> std::io::Result<()> {
     let opts: CliOptions = CliOptions::from_args();
     let addr = format!("{}:{}", opts.host, opts.port);
 
+    let root_key = match (opts.root_key, &opts.db_dir) {
+        (Some(root_key), _) => root_key,
+        // Databases that outlive the server need a root key that does too.
+        (None, Some(_)) => {
+            return Err(std::io::Error::other(
+                "--root-key or EZDB_ROOT_KEY is required with --db-dir",
+            ))
+        }
+        // The key goes straight to the terminal, so that it never ends up in the logs.
+        (None, None) => {
+            let generated = ezdb::credentials::generate_root_key();
+            warn!("no --root-key given, generated one and printed it to stderr");
+            eprintln!("root key: {}", generated);
+            generated
+        }
+    };
     let persistence = match opts.db_dir {
         None => ezdb::persistence::SqliteFactory::InMemory,
         Some(dir) => ezdb::persistence::SqliteFactory::FileSystem { dir },
     };
-    let core = ezdb::core::RoutingActor::new(persistence).start();
+    let credentials = ezdb::credentials::CredentialStore::open(&persistence, &root_key)
+        .map(|store| ezdb::credentials::CredentialActor::new(store).start())
+        .map_err(|e| std::io::Error::other(format!("{:?}", e)))?;
+    let default_timeout = match opts.default_timeout_ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    };
+    let hardening = if opts.trust_admin_sql {
+        None
+    } else {
+        Some(ezdb::persistence::SqliteLimits::default())
+    };
+    // In-memory databases have no readers, and are never closed.
+    let routing = ezdb::core::RoutingConfig {
+        read_connections: opts.read_connections,
+        max_open: opts.max_open_databases,
+        idle_timeout: Duration::from_secs(opts.idle_timeout_secs),
+        default_timeout,
+        max_rows: opts.max_rows,
+        hardening,
+    };
+    let core = ezdb::core::RoutingActor::with_config(persistence, routing).start();
     HttpServer::new(move || {
         App::new()
             .data(core.clone())
+            .data(credentials.clone())
             .wrap(middleware::Logger::default())
+            .service(ezdb::server::admin_service())
             .service(ezdb::server::rest_service())
     })
     .bind(&addr)?
@@ -34,4 +75,31 @@ struct CliOptions {
     port: usize,
     #[structopt(long, parse(from_os_str))]
     db_dir: Option<PathBuf>,
+    /// Bearer token that may manage admin keys for every project. Required with
+    /// --db-dir. Without it, one is generated and printed to stderr.
+    #[structopt(long, env = "EZDB_ROOT_KEY")]
+    root_key: Option<String>,
+    /// Read-only connections per database, so that queries don't wait behind writes.
+    #[structopt(long, default_value = "2")]
+    read_connections: usize,
+    /// Databases to keep open at once. Past this, the least recently used one is closed.
+    /// Without --db-dir, databases are never closed, since that would lose their data.
+    #[structopt(long, default_value = "1024")]
+    max_open_databases: usize,
+    /// Close databases that haven't been used for this many seconds, or never if 0.
+    #[structopt(long, default_value = "300")]
+    idle_timeout_secs: u64,
+    /// Abort statements that run for longer than this many milliseconds, unless their
+    /// template sets its own `timeoutMs`. 0 means no limit.
+    #[structopt(long, default_value = "30000")]
+    default_timeout_ms: u64,
+    /// The most rows a query may return at once. Named queries can page through more
+    /// with `limit` and `pageToken`.
+    #[structopt(long, default_value = "10000")]
+    max_rows: usize,
+    /// Let admin SQL attach other database files, `VACUUM INTO` them, and go past the
+    /// default limits on statements. Only safe when every admin could already read and
+    /// write everything the server can.
+    #[structopt(long)]
+    trust_admin_sql: bool,
 }
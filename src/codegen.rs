@@ -0,0 +1,503 @@
+use crate::analyzer::{is_auth_param, ColumnSignature, Schema, TemplateSignature};
+use std::fmt::Write;
+
+const HEADER: &str = "Generated by ezdb-codegen from the database's policy. Do not edit.";
+
+/// The storage classes a column's declared type maps to, following SQLite's rules for
+/// determining column affinity. Columns without a declared type could hold anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Affinity {
+    Integer,
+    Real,
+    Text,
+    Any,
+}
+
+fn affinity(column: &ColumnSignature) -> Affinity {
+    let decl_type = match column.decl_type {
+        Some(ref t) => t.to_ascii_uppercase(),
+        None => return Affinity::Any,
+    };
+    if decl_type.contains("INT") {
+        Affinity::Integer
+    } else if ["CHAR", "CLOB", "TEXT"]
+        .iter()
+        .any(|t| decl_type.contains(t))
+    {
+        Affinity::Text
+    } else if ["REAL", "FLOA", "DOUB"]
+        .iter()
+        .any(|t| decl_type.contains(t))
+    {
+        Affinity::Real
+    } else {
+        Affinity::Any
+    }
+}
+
+// The params that the caller must provide. `:auth.*` params are filled in by the server.
+fn caller_params(template: &TemplateSignature) -> impl Iterator<Item = &String> {
+    template.params.iter().filter(|p| !is_auth_param(p))
+}
+
+fn words(raw: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut cur = String::new();
+    let mut prev_lower = false;
+    for c in raw.chars() {
+        if !c.is_ascii_alphanumeric() {
+            if !cur.is_empty() {
+                words.push(std::mem::take(&mut cur));
+            }
+            prev_lower = false;
+            continue;
+        }
+        if c.is_ascii_uppercase() && prev_lower {
+            words.push(std::mem::take(&mut cur));
+        }
+        prev_lower = c.is_ascii_lowercase() || c.is_ascii_digit();
+        cur.push(c.to_ascii_lowercase());
+    }
+    if !cur.is_empty() {
+        words.push(cur);
+    }
+    words
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(c) => c.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+fn pascal_case(raw: &str) -> String {
+    let ident: String = words(raw).iter().map(|w| capitalize(w)).collect();
+    match ident.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => ident,
+        _ => format!("T{}", ident),
+    }
+}
+
+fn camel_case(raw: &str) -> String {
+    let pascal = pascal_case(raw);
+    let mut chars = pascal.chars();
+    match chars.next() {
+        Some(c) => c.to_ascii_lowercase().to_string() + chars.as_str(),
+        None => pascal,
+    }
+}
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use",
+    "where", "while",
+];
+
+fn snake_case(raw: &str) -> String {
+    let ident = words(raw).join("_");
+    match ident.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => {}
+        _ => return format!("_{}", ident),
+    }
+    if RUST_KEYWORDS.contains(&ident.as_str()) {
+        format!("{}_", ident)
+    } else {
+        ident
+    }
+}
+
+/// Generates a TypeScript module with one method per template, built on `got` like the
+/// hand-written client.
+pub fn typescript(schema: &Schema) -> String {
+    let mut out = String::new();
+    writeln!(out, "// {}", HEADER).unwrap();
+    out.push_str(TYPESCRIPT_PRELUDE);
+    for q in &schema.queries {
+        typescript_types(&mut out, q);
+    }
+    for m in &schema.mutations {
+        typescript_types(&mut out, m);
+    }
+
+    out.push_str("export class Client {\n  private client: Got;\n");
+    out.push_str(TYPESCRIPT_CONSTRUCTOR);
+    for q in &schema.queries {
+        let ty = pascal_case(&q.name);
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "  async {}(params: {}Params): Promise<{}Row[]> {{",
+            camel_case(&q.name),
+            ty,
+            ty
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "    return this.call(\"GET\", {:?}, {});",
+            q.name,
+            typescript_params(q)
+        )
+        .unwrap();
+        out.push_str("  }\n");
+    }
+    for m in &schema.mutations {
+        let ty = pascal_case(&m.name);
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "  async {}(params: {}Params): Promise<MutationResult<{}Row>> {{",
+            camel_case(&m.name),
+            ty,
+            ty
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "    return this.call(\"POST\", {:?}, {});",
+            m.name,
+            typescript_params(m)
+        )
+        .unwrap();
+        out.push_str("  }\n");
+    }
+    out.push_str(TYPESCRIPT_CALL);
+    out.push_str("}\n");
+    out.push_str(TYPESCRIPT_ERROR);
+    out
+}
+
+// Mutations have a row type too, for the columns of their `RETURNING` clause.
+fn typescript_types(out: &mut String, template: &TemplateSignature) {
+    let ty = pascal_case(&template.name);
+    writeln!(out, "export interface {}Params {{", ty).unwrap();
+    for p in caller_params(template) {
+        writeln!(out, "  readonly {}: Value;", camel_case(p)).unwrap();
+    }
+    out.push_str("}\n");
+    writeln!(out, "export interface {}Row {{", ty).unwrap();
+    for c in &template.columns {
+        let ts_type = match affinity(c) {
+            Affinity::Integer | Affinity::Real => "number | null",
+            Affinity::Text => "string | null",
+            Affinity::Any => "unknown",
+        };
+        writeln!(out, "  readonly {:?}: {};", c.name, ts_type).unwrap();
+    }
+    out.push_str("}\n\n");
+}
+
+fn typescript_params(template: &TemplateSignature) -> String {
+    let fields: Vec<String> = caller_params(template)
+        .map(|p| format!("{:?}: params.{}", p, camel_case(p)))
+        .collect();
+    if fields.is_empty() {
+        return "{}".to_owned();
+    }
+    format!("{{ {} }}", fields.join(", "))
+}
+
+const TYPESCRIPT_PRELUDE: &str = r#"import got, { Got } from "got";
+
+export type Value = number | string | boolean | null | Value[] | { [key: string]: Value };
+
+export interface ClientConfig {
+  readonly address?: string;
+  readonly projectId: string;
+  readonly databaseId?: string;
+  // A JWT identifying the end user, signed with the project's configured key.
+  readonly token?: string;
+}
+
+export interface MutationResult<Row> {
+  readonly changes: number;
+  readonly lastInsertRowid: number;
+  readonly rows: Row[];
+}
+
+"#;
+
+const TYPESCRIPT_CONSTRUCTOR: &str = r#"  constructor({
+    address = "http://localhost:9000",
+    projectId,
+    databaseId = "default",
+    token,
+  }: ClientConfig) {
+    this.client = got.extend({
+      prefixUrl: `${address}/v0/${projectId}/${databaseId}`,
+      headers: token ? { authorization: `Bearer ${token}` } : {},
+      throwHttpErrors: false,
+      allowGetBody: true,
+    });
+  }
+"#;
+
+const TYPESCRIPT_CALL: &str = r#"
+  private async call(
+    method: "GET" | "POST",
+    name: string,
+    params: { [key: string]: Value }
+  ): Promise<any> {
+    const response = await this.client(`named/${name}`, { method, json: params });
+    if (response.statusCode !== 200) {
+      throw new ApiError(response.statusCode, response.body);
+    }
+    return JSON.parse(response.body);
+  }
+"#;
+
+const TYPESCRIPT_ERROR: &str = r#"
+export class ApiError extends Error {
+  constructor(statusCode: number, body: string) {
+    super(body);
+    this.name = statusCode.toString();
+  }
+}
+"#;
+
+/// Generates the `Cargo.toml` and `src/lib.rs` of a Rust crate with one method per
+/// template, built on `reqwest`'s blocking client.
+pub fn rust_crate(schema: &Schema, crate_name: &str) -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "Cargo.toml",
+            RUST_MANIFEST.replace("{crate_name}", crate_name),
+        ),
+        ("src/lib.rs", rust_lib(schema)),
+    ]
+}
+
+fn rust_lib(schema: &Schema) -> String {
+    let mut out = String::new();
+    writeln!(out, "//! {}", HEADER).unwrap();
+    out.push_str(RUST_PRELUDE);
+    for q in &schema.queries {
+        rust_types(&mut out, q);
+    }
+    for m in &schema.mutations {
+        rust_types(&mut out, m);
+    }
+
+    out.push_str("impl Client {\n");
+    for q in &schema.queries {
+        let ty = pascal_case(&q.name);
+        writeln!(
+            out,
+            "    pub fn {}(&self, params: &{}Params) -> Result<Vec<{}Row>, Error> {{",
+            snake_case(&q.name),
+            ty,
+            ty
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "        self.call(reqwest::Method::GET, {:?}, params)",
+            q.name
+        )
+        .unwrap();
+        out.push_str("    }\n\n");
+    }
+    for m in &schema.mutations {
+        let ty = pascal_case(&m.name);
+        writeln!(
+            out,
+            "    pub fn {}(&self, params: &{}Params) -> Result<MutationResult<{}Row>, Error> {{",
+            snake_case(&m.name),
+            ty,
+            ty
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "        self.call(reqwest::Method::POST, {:?}, params)",
+            m.name
+        )
+        .unwrap();
+        out.push_str("    }\n\n");
+    }
+    out.push_str("}\n");
+    out
+}
+
+// Mutations have a row type too, for the columns of their `RETURNING` clause.
+fn rust_types(out: &mut String, template: &TemplateSignature) {
+    let ty = pascal_case(&template.name);
+    writeln!(out, "#[derive(Debug, Clone, Default, Serialize)]").unwrap();
+    writeln!(out, "pub struct {}Params {{", ty).unwrap();
+    for p in caller_params(template) {
+        writeln!(out, "    #[serde(rename = {:?})]", p).unwrap();
+        writeln!(out, "    pub {}: serde_json::Value,", snake_case(p)).unwrap();
+    }
+    out.push_str("}\n\n");
+    writeln!(out, "#[derive(Debug, Clone, Deserialize)]").unwrap();
+    writeln!(out, "pub struct {}Row {{", ty).unwrap();
+    for c in &template.columns {
+        let rust_type = match affinity(c) {
+            Affinity::Integer => "Option<i64>",
+            Affinity::Real => "Option<f64>",
+            Affinity::Text => "Option<String>",
+            Affinity::Any => "serde_json::Value",
+        };
+        writeln!(out, "    #[serde(rename = {:?})]", c.name).unwrap();
+        writeln!(out, "    pub {}: {},", snake_case(&c.name), rust_type).unwrap();
+    }
+    out.push_str("}\n\n");
+}
+
+const RUST_MANIFEST: &str = r#"[package]
+name = "{crate_name}"
+version = "0.1.0"
+edition = "2018"
+
+[dependencies]
+reqwest = { version = "0.11", features = ["blocking", "json"] }
+serde = { version = "1.0", features = ["derive"] }
+serde_json = "1.0"
+"#;
+
+const RUST_PRELUDE: &str = r#"
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug)]
+pub enum Error {
+    Http(reqwest::Error),
+    Api { status: u16, body: String },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MutationResult<Row> {
+    pub changes: u64,
+    pub last_insert_rowid: i64,
+    pub rows: Vec<Row>,
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Error {
+        Error::Http(err)
+    }
+}
+
+pub struct Client {
+    http: reqwest::blocking::Client,
+    prefix: String,
+    token: Option<String>,
+}
+
+impl Client {
+    pub fn new(address: &str, project_id: &str, database_id: &str) -> Client {
+        Client {
+            http: reqwest::blocking::Client::new(),
+            prefix: format!("{}/v0/{}/{}", address, project_id, database_id),
+            token: None,
+        }
+    }
+
+    /// Authenticates requests with a JWT signed with the project's configured key.
+    pub fn with_token(mut self, token: &str) -> Client {
+        self.token = Some(token.to_owned());
+        self
+    }
+
+    fn call<P: Serialize, R: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        name: &str,
+        params: &P,
+    ) -> Result<R, Error> {
+        let mut req = self
+            .http
+            .request(method, &format!("{}/named/{}", self.prefix, name))
+            .json(params);
+        if let Some(ref token) = self.token {
+            req = req.bearer_auth(token);
+        }
+        let resp = req.send()?;
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(Error::Api {
+                status: status.as_u16(),
+                body: resp.text()?,
+            });
+        }
+        Ok(resp.json()?)
+    }
+}
+
+"#;
+
+#[cfg(test)]
+mod test {
+    use super::{camel_case, pascal_case, rust_crate, snake_case, typescript};
+    use crate::analyzer::{ColumnSignature, Schema, TemplateSignature};
+
+    fn schema() -> Schema {
+        Schema {
+            queries: vec![TemplateSignature {
+                name: "get_person".to_owned(),
+                params: vec![":id".to_owned(), ":auth.uid".to_owned()],
+                columns: vec![
+                    ColumnSignature {
+                        name: "name".to_owned(),
+                        decl_type: Some("TEXT".to_owned()),
+                    },
+                    ColumnSignature {
+                        name: "COUNT(1)".to_owned(),
+                        decl_type: None,
+                    },
+                ],
+            }],
+            mutations: vec![TemplateSignature {
+                name: "addPerson".to_owned(),
+                params: vec![":name".to_owned()],
+                columns: vec![],
+            }],
+        }
+    }
+
+    #[test]
+    fn identifiers() {
+        assert_eq!(pascal_case("get_person"), "GetPerson");
+        assert_eq!(camel_case(":id"), "id");
+        assert_eq!(snake_case("addPerson"), "add_person");
+        assert_eq!(snake_case("COUNT(1)"), "count_1");
+        assert_eq!(snake_case("type"), "type_");
+    }
+
+    #[test]
+    fn typescript_client() {
+        let ts = typescript(&schema());
+        assert!(ts.contains("export interface GetPersonParams {\n  readonly id: Value;\n}"));
+        assert!(
+            ts.contains("  readonly \"name\": string | null;\n  readonly \"COUNT(1)\": unknown;")
+        );
+        assert!(ts.contains("async getPerson(params: GetPersonParams): Promise<GetPersonRow[]>"));
+        assert!(
+            ts.contains("return this.call(\"POST\", \"addPerson\", { \":name\": params.name });")
+        );
+        assert!(ts.contains(
+            "async addPerson(params: AddPersonParams): Promise<MutationResult<AddPersonRow>>"
+        ));
+        assert!(!ts.contains(":auth.uid"));
+    }
+
+    #[test]
+    fn rust_client() {
+        let files = rust_crate(&schema(), "people-client");
+        assert!(files[0].1.contains("name = \"people-client\""));
+        let lib = &files[1].1;
+        assert!(lib.contains("    #[serde(rename = \":id\")]\n    pub id: serde_json::Value,"));
+        assert!(lib
+            .contains("    #[serde(rename = \"COUNT(1)\")]\n    pub count_1: serde_json::Value,"));
+        assert!(lib.contains(
+            "pub fn get_person(&self, params: &GetPersonParams) -> Result<Vec<GetPersonRow>, Error>"
+        ));
+        assert!(
+            lib.contains("pub fn add_person(&self, params: &AddPersonParams) -> Result<MutationResult<AddPersonRow>, Error>")
+        );
+    }
+}
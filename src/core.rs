@@ -1,110 +1,483 @@
-use crate::persistence::{Persistence, PersistenceError, PersistenceResult, SqliteFactory};
+use crate::persistence::{
+    Persistence, PersistenceError, PersistenceResult, RowFormat, SqliteFactory, SqliteLimits,
+    SqlitePersistence, Timed,
+};
+use crate::rules::{Caller, Rule};
 use crate::tokens::DatabaseAddress;
+use crate::values::ValueEncoding;
 use actix::prelude::*;
-use crossbeam_channel::Sender;
-use futures::FutureExt;
+use crossbeam_channel::{Receiver, SendTimeoutError, Sender, TryRecvError};
+use futures::future::{BoxFuture, Shared};
+use futures::task::AtomicWaker;
+use futures::{FutureExt, StreamExt};
 use log::debug;
 use rusqlite::InterruptHandle;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use std::collections::hash_map::Entry;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
 use std::{
     collections::{BTreeMap, HashMap},
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
 };
 
 /// `RoutingActor` supervises all the active databases.
 pub struct RoutingActor {
     persistence: SqliteFactory,
-    actors: HashMap<DatabaseAddress, Addr<CoreActor>>,
+    config: RoutingConfig,
+    actors: HashMap<DatabaseAddress, OpenDatabase>,
+    // Databases that have been closed, until their workers finish the jobs they already
+    // had. Opening one again before then would give its file a second writer.
+    closing: HashMap<DatabaseAddress, Closing>,
+    metrics: RoutingMetrics,
+}
+
+// Completes once the database's connections are closed.
+type Closing = Shared<BoxFuture<'static, ()>>;
+
+struct OpenDatabase {
+    addr: Addr<CoreActor>,
+    last_used: Instant,
 }
+
+/// Limits on the databases that the `RoutingActor` keeps open. Every open database has
+/// a thread and SQLite connection for writing, plus one for each reader.
+#[derive(Debug, Clone)]
+pub struct RoutingConfig {
+    /// The number of read-only connections each file-backed database has for serving
+    /// queries, so they don't wait behind writes.
+    pub read_connections: usize,
+    /// Once this many databases are open, the least recently used one is closed to make
+    /// room for the next.
+    pub max_open: usize,
+    /// Databases that haven't been used for this long are closed. Zero means never.
+    pub idle_timeout: Duration,
+    /// How long a statement may run before it's aborted, unless its template sets its
+    /// own `timeoutMs`. `None` means no limit.
+    pub default_timeout: Option<Duration>,
+    /// The most rows a query may return at once. Named queries can be paged through to
+    /// read more than this.
+    pub max_rows: usize,
+    /// Hardens every database's connections against admin SQL that reaches for other
+    /// files, holding statements to these limits. `None` trusts admins with anything
+    /// SQLite allows.
+    pub hardening: Option<SqliteLimits>,
+}
+
+impl Default for RoutingConfig {
+    fn default() -> RoutingConfig {
+        RoutingConfig {
+            read_connections: 2,
+            max_open: 1024,
+            idle_timeout: Duration::from_secs(300),
+            default_timeout: Some(Duration::from_secs(30)),
+            max_rows: 10_000,
+            hardening: Some(SqliteLimits::default()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoutingMetrics {
+    /// The number of databases open right now.
+    pub open: usize,
+    /// The number of times a database has been opened since the server started.
+    pub opened: u64,
+    /// The number of databases closed for being idle.
+    pub idle_evictions: u64,
+    /// The number of databases closed to stay under `RoutingConfig::max_open`.
+    pub capacity_evictions: u64,
+}
+
 impl RoutingActor {
     pub fn new(persistence: SqliteFactory) -> RoutingActor {
+        RoutingActor::with_config(persistence, RoutingConfig::default())
+    }
+
+    /// In-memory databases lose their data when they're closed, so they never are,
+    /// whatever `config` says.
+    pub fn with_config(persistence: SqliteFactory, config: RoutingConfig) -> RoutingActor {
+        let config = match persistence {
+            SqliteFactory::InMemory => RoutingConfig {
+                max_open: usize::MAX,
+                idle_timeout: Duration::from_secs(0),
+                ..config
+            },
+            SqliteFactory::FileSystem { .. } => config,
+        };
         RoutingActor {
             persistence,
+            config,
             actors: HashMap::new(),
+            closing: HashMap::new(),
+            metrics: RoutingMetrics::default(),
+        }
+    }
+
+    fn evict_idle(&mut self, ctx: &mut Context<Self>) {
+        let now = Instant::now();
+        let idle: Vec<DatabaseAddress> = self
+            .actors
+            .iter()
+            .filter(|(_, db)| now.duration_since(db.last_used) >= self.config.idle_timeout)
+            .map(|(db_addr, _)| db_addr.clone())
+            .collect();
+        for db_addr in idle {
+            debug!("closing idle database {}", db_addr);
+            self.close(&db_addr, ctx);
+            self.metrics.idle_evictions += 1;
+        }
+    }
+
+    fn evict_least_recently_used(&mut self, ctx: &mut Context<Self>) {
+        let lru = self
+            .actors
+            .iter()
+            .min_by_key(|(_, db)| db.last_used)
+            .map(|(db_addr, _)| db_addr.clone());
+        if let Some(db_addr) = lru {
+            debug!("closing least recently used database {}", db_addr);
+            self.close(&db_addr, ctx);
+            self.metrics.capacity_evictions += 1;
+        }
+    }
+
+    // Requests that are already queued for the database still run before it closes.
+    fn close(&mut self, db_addr: &DatabaseAddress, ctx: &mut Context<Self>) {
+        if let Some(db) = self.actors.remove(db_addr) {
+            let closing = db
+                .addr
+                .send(EzdbMessage::Logistics(LogisticsMessage::Shutdown))
+                .map(|_| ())
+                .boxed()
+                .shared();
+            self.closing.insert(db_addr.clone(), closing.clone());
+            let db_addr = db_addr.clone();
+            ctx.spawn(closing.into_actor(self).map(move |(), act, _| {
+                act.still_closing(&db_addr);
+            }));
+        }
+        self.metrics.open = self.actors.len();
+    }
+
+    // The database's `Closing`, if it hasn't finished closing. One that has is forgotten.
+    fn still_closing(&mut self, db_addr: &DatabaseAddress) -> Option<Closing> {
+        let closing = self.closing.get(db_addr)?;
+        if closing.peek().is_none() {
+            return Some(closing.clone());
+        }
+        self.closing.remove(db_addr);
+        None
+    }
+
+    // Opens the database, first waiting for it to finish closing if it is.
+    fn route(
+        &mut self,
+        db_addr: DatabaseAddress,
+        ctx: &mut Context<Self>,
+    ) -> ResponseActFuture<Self, PersistenceResult<Addr<CoreActor>>> {
+        if let Some(closing) = self.still_closing(&db_addr) {
+            debug!("waiting for database {} to close", db_addr);
+            return Box::pin(
+                closing
+                    .into_actor(self)
+                    .then(move |(), act, ctx| act.route(db_addr, ctx)),
+            );
         }
+        Box::pin(fut::ready(self.open(db_addr, ctx)))
+    }
+
+    fn open(
+        &mut self,
+        db_addr: DatabaseAddress,
+        ctx: &mut Context<Self>,
+    ) -> PersistenceResult<Addr<CoreActor>> {
+        let now = Instant::now();
+        if let Some(db) = self.actors.get_mut(&db_addr) {
+            db.last_used = now;
+            return Ok(db.addr.clone());
+        }
+        while !self.actors.is_empty() && self.actors.len() >= self.config.max_open {
+            self.evict_least_recently_used(ctx);
+        }
+        let config = &self.config;
+        let limit = |conn: SqlitePersistence| {
+            let conn = conn
+                .with_default_timeout(config.default_timeout)
+                .with_max_rows(config.max_rows);
+            Ok(Timed::new(match &config.hardening {
+                Some(limits) => conn.harden(limits)?,
+                None => conn,
+            }))
+        };
+        let writer = limit(self.persistence.open(&db_addr)?)?;
+        let readers = self
+            .persistence
+            .open_readers(&db_addr, config.read_connections)?
+            .into_iter()
+            .map(limit)
+            .collect::<PersistenceResult<_>>()?;
+        let addr = CoreActor::with_readers(writer, readers).start();
+        self.actors.insert(
+            db_addr,
+            OpenDatabase {
+                addr: addr.clone(),
+                last_used: now,
+            },
+        );
+        self.metrics.opened += 1;
+        self.metrics.open = self.actors.len();
+        Ok(addr)
     }
 }
 
 impl Actor for RoutingActor {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        // Sweeping twice per timeout means no database stays open for more than 1.5x
+        // the timeout after its last use.
+        if self.config.idle_timeout > Duration::from_secs(0) {
+            ctx.run_interval(self.config.idle_timeout / 2, |act, ctx| act.evict_idle(ctx));
+        }
+    }
 }
 
 impl Message for DatabaseAddress {
     type Result = PersistenceResult<Addr<CoreActor>>;
 }
 impl Handler<DatabaseAddress> for RoutingActor {
-    type Result = PersistenceResult<Addr<CoreActor>>;
+    type Result = ResponseActFuture<Self, PersistenceResult<Addr<CoreActor>>>;
 
-    fn handle(
-        &mut self,
-        db_addr: DatabaseAddress,
-        _ctx: &mut Context<Self>,
-    ) -> PersistenceResult<Addr<CoreActor>> {
-        let addr = match self.actors.entry(db_addr) {
-            Entry::Occupied(occ) => occ.get().clone(),
-            Entry::Vacant(vac) => {
-                let db = crate::persistence::Timed::new(self.persistence.open(vac.key())?);
-                vac.insert(CoreActor::new(db).start()).clone()
-            }
-        };
-        Ok(addr)
+    fn handle(&mut self, db_addr: DatabaseAddress, ctx: &mut Context<Self>) -> Self::Result {
+        self.route(db_addr, ctx)
+    }
+}
+
+/// Fetches the `RoutingActor`'s metrics.
+pub struct GetRoutingMetrics;
+impl Message for GetRoutingMetrics {
+    type Result = RoutingMetrics;
+}
+impl Handler<GetRoutingMetrics> for RoutingActor {
+    type Result = MessageResult<GetRoutingMetrics>;
+
+    fn handle(&mut self, _: GetRoutingMetrics, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.metrics.clone())
     }
 }
 
 struct Job<I, O> {
+    id: u64,
     input: I,
     output: futures::channel::oneshot::Sender<O>,
     generation: usize,
 }
-/// `CoreActor` manages connections to a given database.
+/// `CoreActor` manages connections to a given database: one writer, and optionally a
+/// pool of read-only connections that serve queries alongside it.
 pub struct CoreActor {
-    queue: Sender<Job<DataMessage, PersistenceResult<String>>>,
-    interrupt_handle: InterruptHandle,
+    queue: Sender<DataJob>,
+    read_queue: Option<Sender<DataJob>>,
+    interrupt_handles: Vec<InterruptHandle>,
+    // Each worker's cancel flag, in the same order as its interrupt handle.
+    cancel_flags: Vec<Arc<AtomicBool>>,
     generation: Arc<AtomicUsize>,
+    jobs: Arc<Mutex<JobTable>>,
+    next_job_id: u64,
+    draining: bool,
+    // Ends once every worker has exited, closing its connection.
+    workers_exited: Option<futures::channel::mpsc::Receiver<()>>,
+}
+
+type DataJob = Job<DataMessage, PersistenceResult<String>>;
+
+// Every job that has been queued but not answered yet, by id. A job that's missing
+// from the table when a worker takes it off the queue has been cancelled.
+type JobTable = BTreeMap<u64, JobState>;
+
+struct JobState {
+    kind: &'static str,
+    detail: Option<String>,
+    queued_at: Instant,
+    // The index of the worker (and its interrupt handle and cancel flag) running the
+    // job, once it starts.
+    worker: Option<usize>,
+}
+
+/// A request that a database has accepted but not answered yet.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobInfo {
+    pub id: u64,
+    /// What the request does, like `queryNamed` or `mutateRaw`.
+    pub kind: &'static str,
+    /// The template a named request runs, or the SQL of a raw one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Whether the job is running, rather than waiting in the queue.
+    pub running: bool,
+    /// How long ago the job was queued.
+    pub age_ms: u64,
 }
 
 const MAILBOX_SIZE: usize = 16;
 impl CoreActor {
-    pub fn new<P: Persistence + 'static>(mut persistence: P) -> CoreActor {
-        let interrupt_handle = (&persistence).get_interrupt_handle();
-        let (tx, rx) =
-            crossbeam_channel::bounded::<Job<DataMessage, PersistenceResult<String>>>(MAILBOX_SIZE);
-        let signal = Arc::new(AtomicUsize::new(0));
-        let signal2 = signal.clone();
-        std::thread::spawn(move || {
-            let signal = signal.clone();
-            while let Ok(job) = rx.recv() {
-                let r = if signal.load(Ordering::Relaxed) > job.generation {
-                    Err(PersistenceError::Interrupted)
-                } else {
-                    handle_data_request(&mut persistence, job.input)
-                };
-                let _ = job.output.send(r);
+    pub fn new<P: Persistence + 'static>(persistence: P) -> CoreActor {
+        CoreActor::with_readers(persistence, Vec::new())
+    }
+
+    /// Like `new`, but queries are served by `readers`, each on its own thread.
+    pub fn with_readers<P: Persistence + 'static>(writer: P, readers: Vec<P>) -> CoreActor {
+        let generation = Arc::new(AtomicUsize::new(0));
+        let jobs = Arc::new(Mutex::new(JobTable::new()));
+        let mut interrupt_handles = vec![writer.get_interrupt_handle()];
+        let mut cancel_flags = vec![writer.get_cancel_flag()];
+        // Each worker holds a sender until it exits, so the receiver ends after the last.
+        let (exited, workers_exited) = futures::channel::mpsc::channel(0);
+        let (tx, rx) = crossbeam_channel::bounded::<DataJob>(MAILBOX_SIZE);
+        let spawn = |persistence, worker, queue| {
+            let exited = exited.clone();
+            spawn_worker(
+                persistence,
+                worker,
+                queue,
+                generation.clone(),
+                jobs.clone(),
+                exited,
+            )
+        };
+        spawn(writer, 0, rx);
+        let read_queue = if readers.is_empty() {
+            None
+        } else {
+            let (tx, rx) = crossbeam_channel::bounded::<DataJob>(MAILBOX_SIZE * readers.len());
+            for reader in readers {
+                let worker = interrupt_handles.len();
+                interrupt_handles.push(reader.get_interrupt_handle());
+                cancel_flags.push(reader.get_cancel_flag());
+                spawn(reader, worker, rx.clone());
             }
-        });
+            Some(tx)
+        };
+        drop(exited);
         CoreActor {
             queue: tx,
-            interrupt_handle,
-            generation: signal2,
+            read_queue,
+            interrupt_handles,
+            cancel_flags,
+            generation,
+            jobs,
+            next_job_id: 0,
+            draining: false,
+            workers_exited: Some(workers_exited),
         }
     }
 
     pub fn interrupt(&self) {
         self.generation.fetch_add(1, Ordering::Relaxed);
-        self.interrupt_handle.interrupt();
+        for handle in &self.interrupt_handles {
+            handle.interrupt();
+        }
+    }
+
+    /// The jobs that are queued or running, oldest first.
+    pub fn jobs(&self) -> Vec<JobInfo> {
+        let now = Instant::now();
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter()
+            .map(|(id, job)| JobInfo {
+                id: *id,
+                kind: job.kind,
+                detail: job.detail.clone(),
+                running: job.worker.is_some(),
+                age_ms: now.duration_since(job.queued_at).as_millis() as u64,
+            })
+            .collect()
+    }
+
+    /// Cancels one job, leaving the others alone. A queued job is dropped before it
+    /// starts, and a running one is stopped at its next statement or progress check, so
+    /// either way it fails with `Interrupted`.
+    pub fn cancel(&self, id: u64) -> PersistenceResult<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.remove(&id).ok_or(PersistenceError::NoSuchJob(id))?;
+        // The worker removes a job from the table before answering it, so while the lock
+        // is held, the worker is still running this job and not some later one.
+        // The flag stops a job between statements, where an interrupt would be missed.
+        if let Some(worker) = job.worker {
+            self.cancel_flags[worker].store(true, Ordering::Relaxed);
+            self.interrupt_handles[worker].interrupt();
+        }
+        Ok(())
+    }
+
+    fn queue_for(&self, msg: &DataMessage) -> &Sender<DataJob> {
+        let read_only = matches!(
+            msg,
+            DataMessage::QueryNamed(..)
+                | DataMessage::QueryPage(..)
+                | DataMessage::StreamNamed(..)
+                | DataMessage::StreamRaw(..)
+                | DataMessage::QueryRaw(..)
+                | DataMessage::FetchPolicy
+                | DataMessage::FetchPolicyHistory
+                | DataMessage::FetchPolicyRevision(_)
+                | DataMessage::FetchSchema
+                | DataMessage::ListMigrations
+        );
+        match &self.read_queue {
+            Some(read_queue) if read_only => read_queue,
+            _ => &self.queue,
+        }
     }
 }
 
+// Several workers may share a queue, each taking the next job when it's free.
+fn spawn_worker<P: Persistence + 'static>(
+    mut persistence: P,
+    worker: usize,
+    queue: Receiver<DataJob>,
+    generation: Arc<AtomicUsize>,
+    jobs: Arc<Mutex<JobTable>>,
+    exited: futures::channel::mpsc::Sender<()>,
+) {
+    let cancel_flag = persistence.get_cancel_flag();
+    std::thread::spawn(move || {
+        let _exited = exited;
+        while let Ok(job) = queue.recv() {
+            let cancelled = match jobs.lock().unwrap().get_mut(&job.id) {
+                Some(state) => {
+                    // Under the lock, so a cancellation of the last job can't leak in.
+                    cancel_flag.store(false, Ordering::Relaxed);
+                    state.worker = Some(worker);
+                    false
+                }
+                None => true,
+            };
+            let r = if cancelled || generation.load(Ordering::Relaxed) > job.generation {
+                Err(PersistenceError::Interrupted)
+            } else {
+                handle_data_request(&mut persistence, job.input)
+            };
+            // A job that's cancelled just as it finishes still reports what it did.
+            jobs.lock().unwrap().remove(&job.id);
+            let _ = job.output.send(r);
+        }
+    });
+}
+
 impl Actor for CoreActor {
     type Context = Context<Self>;
 
+    // Dropping the actor closes the queues, so the worker threads exit (closing their
+    // connections) once they have finished the jobs that are already queued.
     fn stopped(&mut self, _ctx: &mut Self::Context) {
-        self.interrupt();
+        if !self.draining {
+            self.interrupt();
+        }
     }
 }
 
@@ -118,18 +491,249 @@ pub enum EzdbMessage {
 /// Message to interact with the data in the database.
 #[derive(Debug)]
 pub enum DataMessage {
-    QueryNamed(String, BTreeMap<String, Value>),
-    MutateNamed(String, BTreeMap<String, Value>),
-    QueryRaw(String),
-    MutateRaw(String),
+    /// Runs a named query on behalf of an end user, if its rule allows them to. The
+    /// same goes for the other named messages. Rows come back in the given value
+    /// encoding, as they do for every message that returns rows.
+    QueryNamed(Caller, String, BTreeMap<String, Value>, ValueEncoding),
+    /// Like `QueryNamed`, but returns one page of the results.
+    QueryPage(
+        Caller,
+        String,
+        BTreeMap<String, Value>,
+        PageRequest,
+        ValueEncoding,
+    ),
+    /// Like `QueryNamed`, or `QueryPage` given a page, but writes the rows to the sink as
+    /// they're read, in its format and encoding.
+    StreamNamed(
+        Caller,
+        String,
+        BTreeMap<String, Value>,
+        Option<PageRequest>,
+        RowSink,
+    ),
+    MutateNamed(Caller, String, BTreeMap<String, Value>, ValueEncoding),
+    /// Like `MutateNamed`, but writes the returned rows to the sink.
+    StreamMutationNamed(Caller, String, BTreeMap<String, Value>, RowSink),
+    QueryRaw(String, ValueEncoding),
+    /// Like `QueryRaw`, but writes the rows to the sink as they're read.
+    StreamRaw(String, RowSink),
+    MutateRaw(String, ValueEncoding),
+    /// Like `MutateRaw`, but writes the returned rows to the sink.
+    StreamMutationRaw(String, RowSink),
     FetchPolicy,
-    SetPolicy(Policy),
+    /// Changes the policy, if it's still at the given version, on behalf of the given
+    /// author.
+    UpdatePolicy(PolicyChange, Option<u64>, Option<String>),
+    FetchPolicyHistory,
+    FetchPolicyRevision(u64),
+    FetchSchema,
+    Batch(Caller, Vec<BatchStep>, ValueEncoding),
+    ListMigrations,
+    /// Applies the pending migrations in the list, or just checks them on a dry run.
+    ApplyMigrations(Vec<Migration>, bool),
+}
+
+impl DataMessage {
+    // How the message appears in a job listing.
+    fn describe(&self) -> (&'static str, Option<String>) {
+        match self {
+            DataMessage::QueryNamed(_, name, _, _)
+            | DataMessage::QueryPage(_, name, _, _, _)
+            | DataMessage::StreamNamed(_, name, _, _, _) => ("queryNamed", Some(name.clone())),
+            DataMessage::MutateNamed(_, name, _, _)
+            | DataMessage::StreamMutationNamed(_, name, _, _) => {
+                ("mutateNamed", Some(name.clone()))
+            }
+            DataMessage::QueryRaw(sql, _) | DataMessage::StreamRaw(sql, _) => {
+                ("queryRaw", Some(sql.clone()))
+            }
+            DataMessage::MutateRaw(sql, _) | DataMessage::StreamMutationRaw(sql, _) => {
+                ("mutateRaw", Some(sql.clone()))
+            }
+            DataMessage::FetchPolicy => ("fetchPolicy", None),
+            DataMessage::UpdatePolicy(change, _, _) => ("updatePolicy", change.describe()),
+            DataMessage::FetchPolicyHistory => ("fetchPolicyHistory", None),
+            DataMessage::FetchPolicyRevision(revision) => {
+                ("fetchPolicyRevision", Some(revision.to_string()))
+            }
+            DataMessage::FetchSchema => ("fetchSchema", None),
+            DataMessage::ListMigrations => ("listMigrations", None),
+            DataMessage::ApplyMigrations(migrations, _) => {
+                let names: Vec<&str> = migrations.iter().map(|m| m.name.as_str()).collect();
+                ("applyMigrations", Some(names.join(", ")))
+            }
+            DataMessage::Batch(_, steps, _) => {
+                let names: Vec<&str> = steps
+                    .iter()
+                    .map(|step| match step {
+                        BatchStep::Query { name, .. } | BatchStep::Mutation { name, .. } => {
+                            name.as_str()
+                        }
+                    })
+                    .collect();
+                ("batch", Some(names.join(", ")))
+            }
+        }
+    }
+}
+
+/// One named template to run as part of a batch.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum BatchStep {
+    Query {
+        name: String,
+        #[serde(default)]
+        params: BTreeMap<String, Value>,
+    },
+    Mutation {
+        name: String,
+        #[serde(default)]
+        params: BTreeMap<String, Value>,
+    },
+}
+
+/// Where a streamed query writes its rows. They're sent on in chunks as they're
+/// serialized, and at most `ROW_BUFFERED_CHUNKS` are held in memory for a reader that
+/// falls behind. A reader that's still behind after the send timeout fails the query,
+/// rather than holding up the connection for as long as it likes.
+pub struct RowSink {
+    pub format: RowFormat,
+    pub encoding: ValueEncoding,
+    buf: Vec<u8>,
+    // Only taken when the sink is dropped, to end the chunks.
+    chunks: Option<Sender<Vec<u8>>>,
+    reader: Arc<AtomicWaker>,
+    send_timeout: Duration,
+}
+
+/// The chunks that a `RowSink` sends on, in order.
+pub struct RowChunks {
+    chunks: Receiver<Vec<u8>>,
+    reader: Arc<AtomicWaker>,
+}
+
+// Rows are sent on once they fill a chunk this big.
+const ROW_CHUNK_SIZE: usize = 16 * 1024;
+// How many chunks can wait for the reader.
+const ROW_BUFFERED_CHUNKS: usize = 16;
+const DEFAULT_ROW_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl RowSink {
+    /// Returns a sink, and the chunks written to it. The chunks end when the query
+    /// finishes, successfully or not.
+    pub fn new(format: RowFormat, encoding: ValueEncoding) -> (RowSink, RowChunks) {
+        let (tx, rx) = crossbeam_channel::bounded(ROW_BUFFERED_CHUNKS);
+        let reader = Arc::new(AtomicWaker::new());
+        let sink = RowSink {
+            format,
+            encoding,
+            buf: Vec::with_capacity(ROW_CHUNK_SIZE),
+            chunks: Some(tx),
+            reader: reader.clone(),
+            send_timeout: DEFAULT_ROW_SEND_TIMEOUT,
+        };
+        (sink, RowChunks { chunks: rx, reader })
+    }
+
+    /// How long to wait for the reader to make room for a chunk before failing the
+    /// query with `DeadlineExceeded`.
+    pub fn with_send_timeout(mut self, timeout: Duration) -> RowSink {
+        self.send_timeout = timeout;
+        self
+    }
+
+    // Waits, up to the send timeout, for the reader to have room for another chunk.
+    fn send_chunk(&mut self) -> std::io::Result<()> {
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(ROW_CHUNK_SIZE));
+        let chunks = self.chunks.as_ref().expect("chunks are only taken on drop");
+        match chunks.send_timeout(chunk, self.send_timeout) {
+            Ok(()) => {
+                self.reader.wake();
+                Ok(())
+            }
+            Err(SendTimeoutError::Timeout(_)) => Err(std::io::ErrorKind::TimedOut.into()),
+            Err(SendTimeoutError::Disconnected(_)) => Err(std::io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+impl Drop for RowSink {
+    // The reader only sees that the chunks have ended once the sender is gone.
+    fn drop(&mut self) {
+        self.chunks.take();
+        self.reader.wake();
+    }
+}
+
+impl futures::Stream for RowChunks {
+    type Item = Vec<u8>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Vec<u8>>> {
+        // Registering before looking means a chunk sent in between still wakes us.
+        self.reader.register(cx.waker());
+        match self.chunks.try_recv() {
+            Ok(chunk) => std::task::Poll::Ready(Some(chunk)),
+            Err(TryRecvError::Empty) => std::task::Poll::Pending,
+            Err(TryRecvError::Disconnected) => std::task::Poll::Ready(None),
+        }
+    }
+}
+
+impl std::io::Write for RowSink {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= ROW_CHUNK_SIZE {
+            self.send_chunk()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            self.send_chunk()
+        }
+    }
+}
+
+impl std::fmt::Debug for RowSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RowSink")
+            .field("format", &self.format)
+            .field("encoding", &self.encoding)
+            .finish()
+    }
+}
+
+/// Which page of a named query's results to return. Pages are found by offset, so
+/// they only line up if the query's `ORDER BY` gives every row a fixed place.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageRequest {
+    /// The most rows to return, which the server may lower.
+    pub limit: Option<usize>,
+    /// The `nextPageToken` of the previous page, or nothing for the first page.
+    pub page_token: Option<String>,
 }
 
 /// Message to control the logistics of the database.
 #[derive(Debug)]
 pub enum LogisticsMessage {
+    /// Aborts every job that is running or queued.
     Interrupt,
+    /// Stops accepting requests, but lets the queued ones finish. Answers once they
+    /// have, and the database's connections are closed.
+    Shutdown,
+    /// Lists the jobs that are running or queued.
+    ListJobs,
+    /// Aborts one job, by the id it has in `ListJobs`.
+    Cancel(u64),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -143,6 +747,11 @@ pub struct Policy {
 pub struct QueryPolicy {
     pub name: String,
     pub raw_sql: String,
+    #[serde(default)]
+    pub rule: Rule,
+    /// How long the template may run before it's aborted, overriding the server's default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -150,6 +759,94 @@ pub struct QueryPolicy {
 pub struct MutationPolicy {
     pub name: String,
     pub raw_sql: String,
+    #[serde(default)]
+    pub rule: Rule,
+    /// How long the template may run before it's aborted, overriding the server's default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+/// One step in a database's schema history. Each one is applied once, in the order
+/// they're listed.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Migration {
+    pub name: String,
+    pub sql: String,
+}
+
+/// One change to a policy.
+#[derive(Debug)]
+pub enum PolicyChange {
+    /// Replaces every template.
+    Replace(Policy),
+    /// Adds a query, or replaces the one with the same name.
+    PutQuery(QueryPolicy),
+    /// Adds a mutation, or replaces the one with the same name.
+    PutMutation(MutationPolicy),
+    DeleteQuery(String),
+    DeleteMutation(String),
+    /// Goes back to the policy of an earlier revision.
+    Rollback(u64),
+}
+
+impl PolicyChange {
+    /// Returns `policy` with this change made to it, looking up the policy of any
+    /// revision it refers to with `revision`.
+    pub fn apply(
+        self,
+        mut policy: Policy,
+        revision: impl FnOnce(u64) -> PersistenceResult<Policy>,
+    ) -> PersistenceResult<Policy> {
+        match self {
+            PolicyChange::Replace(new) => return Ok(new),
+            PolicyChange::Rollback(n) => return revision(n),
+            PolicyChange::PutQuery(query) => {
+                match policy.queries.iter_mut().find(|q| q.name == query.name) {
+                    Some(existing) => *existing = query,
+                    None => policy.queries.push(query),
+                }
+            }
+            PolicyChange::PutMutation(mutation) => {
+                match policy
+                    .mutations
+                    .iter_mut()
+                    .find(|m| m.name == mutation.name)
+                {
+                    Some(existing) => *existing = mutation,
+                    None => policy.mutations.push(mutation),
+                }
+            }
+            PolicyChange::DeleteQuery(name) => {
+                let i = policy
+                    .queries
+                    .iter()
+                    .position(|q| q.name == name)
+                    .ok_or(PersistenceError::NoSuchQuery(name))?;
+                policy.queries.remove(i);
+            }
+            PolicyChange::DeleteMutation(name) => {
+                let i = policy
+                    .mutations
+                    .iter()
+                    .position(|m| m.name == name)
+                    .ok_or(PersistenceError::NoSuchQuery(name))?;
+                policy.mutations.remove(i);
+            }
+        }
+        Ok(policy)
+    }
+
+    fn describe(&self) -> Option<String> {
+        match self {
+            PolicyChange::Replace(_) => None,
+            PolicyChange::Rollback(revision) => Some(format!("rollback to {}", revision)),
+            PolicyChange::PutQuery(QueryPolicy { name, .. })
+            | PolicyChange::PutMutation(MutationPolicy { name, .. })
+            | PolicyChange::DeleteQuery(name)
+            | PolicyChange::DeleteMutation(name) => Some(name.clone()),
+        }
+    }
 }
 
 impl Message for EzdbMessage {
@@ -159,22 +856,57 @@ impl Message for EzdbMessage {
 impl Handler<EzdbMessage> for CoreActor {
     type Result = ResponseFuture<PersistenceResult<String>>;
 
-    fn handle(&mut self, msg: EzdbMessage, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: EzdbMessage, ctx: &mut Context<Self>) -> Self::Result {
         match msg {
             EzdbMessage::Logistics(LogisticsMessage::Interrupt) => {
                 self.interrupt();
                 Box::pin(std::future::ready(Ok("ok".to_owned())))
             }
+            EzdbMessage::Logistics(LogisticsMessage::Shutdown) => {
+                self.draining = true;
+                ctx.stop();
+                let workers_exited = self.workers_exited.take();
+                Box::pin(async move {
+                    if let Some(mut workers_exited) = workers_exited {
+                        workers_exited.next().await;
+                    }
+                    Ok("ok".to_owned())
+                })
+            }
+            EzdbMessage::Logistics(LogisticsMessage::ListJobs) => {
+                let jobs = serde_json::to_string(&self.jobs()).expect("serialize");
+                Box::pin(std::future::ready(Ok(jobs)))
+            }
+            EzdbMessage::Logistics(LogisticsMessage::Cancel(id)) => {
+                let result = self.cancel(id).map(|_| "ok".to_owned());
+                Box::pin(std::future::ready(result))
+            }
             EzdbMessage::Data(input) => {
+                let id = self.next_job_id;
+                self.next_job_id += 1;
+                let (kind, detail) = input.describe();
+                self.jobs.lock().unwrap().insert(
+                    id,
+                    JobState {
+                        kind,
+                        detail,
+                        queued_at: Instant::now(),
+                        worker: None,
+                    },
+                );
                 let (tx, rx) = futures::channel::oneshot::channel();
                 let job = Job {
+                    id,
                     input,
                     output: tx,
                     generation: self.generation.load(Ordering::Relaxed),
                 };
-                match self.queue.try_send(job) {
+                match self.queue_for(&job.input).try_send(job) {
                     Ok(_) => Box::pin(rx.map(|r| r.unwrap())),
-                    Err(_) => Box::pin(std::future::ready(Err(PersistenceError::Busy))),
+                    Err(_) => {
+                        self.jobs.lock().unwrap().remove(&id);
+                        Box::pin(std::future::ready(Err(PersistenceError::Busy)))
+                    }
                 }
             }
         }
@@ -187,28 +919,80 @@ fn handle_data_request<P: Persistence>(
 ) -> PersistenceResult<String> {
     debug!("handling {:?}", msg);
     match msg {
-        DataMessage::QueryNamed(name, params) => {
-            let data = persistence.query_named(name, params)?;
+        DataMessage::QueryNamed(caller, name, params, encoding) => {
+            let data = persistence.query_named(&caller, name, params, encoding)?;
+            Ok(serde_json::to_string(&data).expect("serialize"))
+        }
+        DataMessage::QueryPage(caller, name, params, page, encoding) => {
+            let data = persistence.query_page(&caller, name, params, page, encoding)?;
+            Ok(serde_json::to_string(&data).expect("serialize"))
+        }
+        DataMessage::QueryRaw(query, encoding) => {
+            let data = persistence.query_raw(query, encoding)?;
+            Ok(serde_json::to_string(&data).expect("serialize"))
+        }
+        DataMessage::StreamNamed(caller, name, params, page, mut sink) => {
+            let (format, encoding) = (sink.format, sink.encoding);
+            let next_page_token = persistence
+                .stream_named(&caller, name, params, page, format, encoding, &mut sink)?;
+            Ok(
+                serde_json::to_string(&json!({ "nextPageToken": next_page_token }))
+                    .expect("serialize"),
+            )
+        }
+        DataMessage::StreamRaw(query, mut sink) => {
+            persistence.stream_raw(query, sink.format, sink.encoding, &mut sink)?;
+            Ok(serde_json::to_string(&()).expect("serialize"))
+        }
+        DataMessage::MutateNamed(caller, name, params, encoding) => {
+            let data = persistence.mutate_named(&caller, name, params, encoding)?;
             Ok(serde_json::to_string(&data).expect("serialize"))
         }
-        DataMessage::QueryRaw(query) => {
-            let data = persistence.query_raw(query)?;
+        DataMessage::StreamMutationNamed(caller, name, params, mut sink) => {
+            let (format, encoding) = (sink.format, sink.encoding);
+            let data = persistence
+                .stream_mutation_named(&caller, name, params, format, encoding, &mut sink)?;
             Ok(serde_json::to_string(&data).expect("serialize"))
         }
-        DataMessage::MutateNamed(name, params) => {
-            let data = persistence.mutate_named(name, params)?;
+        DataMessage::MutateRaw(stmt, encoding) => {
+            let data = persistence.mutate_raw(stmt, encoding)?;
             Ok(serde_json::to_string(&data).expect("serialize"))
         }
-        DataMessage::MutateRaw(stmt) => {
-            let data = persistence.mutate_raw(stmt)?;
+        DataMessage::StreamMutationRaw(stmt, mut sink) => {
+            let data =
+                persistence.stream_mutation_raw(stmt, sink.format, sink.encoding, &mut sink)?;
             Ok(serde_json::to_string(&data).expect("serialize"))
         }
         DataMessage::FetchPolicy => {
             let data = persistence.fetch_policy()?;
             Ok(serde_json::to_string(&data).expect("serialize"))
         }
-        DataMessage::SetPolicy(policy) => {
-            let data = persistence.set_policy(policy)?;
+        DataMessage::UpdatePolicy(change, if_version, author) => {
+            let version = persistence.update_policy(change, if_version, author)?;
+            Ok(serde_json::to_string(&json!({ "version": version })).expect("serialize"))
+        }
+        DataMessage::FetchPolicyHistory => {
+            let data = persistence.policy_history()?;
+            Ok(serde_json::to_string(&data).expect("serialize"))
+        }
+        DataMessage::FetchPolicyRevision(revision) => {
+            let data = persistence.fetch_policy_revision(revision)?;
+            Ok(serde_json::to_string(&data).expect("serialize"))
+        }
+        DataMessage::FetchSchema => {
+            let data = persistence.fetch_schema()?;
+            Ok(serde_json::to_string(&data).expect("serialize"))
+        }
+        DataMessage::Batch(caller, steps, encoding) => {
+            let data = persistence.batch(&caller, steps, encoding)?;
+            Ok(serde_json::to_string(&data).expect("serialize"))
+        }
+        DataMessage::ListMigrations => {
+            let data = persistence.list_migrations()?;
+            Ok(serde_json::to_string(&data).expect("serialize"))
+        }
+        DataMessage::ApplyMigrations(migrations, dry_run) => {
+            let data = persistence.apply_migrations(migrations, dry_run)?;
             Ok(serde_json::to_string(&data).expect("serialize"))
         }
     }
@@ -216,11 +1000,48 @@ fn handle_data_request<P: Persistence>(
 
 #[cfg(test)]
 mod test {
-    use super::{CoreActor, DataMessage, EzdbMessage, LogisticsMessage};
-    use crate::persistence::{PersistenceError, SqlitePersistence};
+    use super::{
+        CoreActor, DataMessage, EzdbMessage, GetRoutingMetrics, LogisticsMessage, RoutingActor,
+        RoutingConfig, RowSink,
+    };
+    use crate::persistence::{PersistenceError, RowFormat, SqliteFactory, SqlitePersistence};
+    use crate::tokens::DatabaseAddress;
+    use crate::values::ValueEncoding;
     use actix::{Actor, Addr};
+    use futures::FutureExt;
     use std::time::Duration;
 
+    #[actix_rt::test]
+    async fn slow_readers_do_not_hold_up_the_connection() {
+        let actor = CoreActor::new(SqlitePersistence::in_memory().unwrap()).start();
+        mutate_raw(&actor, "CREATE TABLE foo (x INTEGER)").await;
+        let (sink, chunks) = RowSink::new(RowFormat::Json, ValueEncoding::Plain);
+        let sink = sink.with_send_timeout(Duration::from_millis(50));
+        // Far more rows than fit in the chunks that are buffered for a reader, which
+        // never reads any of them.
+        let sql = "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n LIMIT 1000000) \
+                   SELECT i FROM n";
+        let streamed = actor.send(EzdbMessage::Data(DataMessage::StreamRaw(
+            sql.to_owned(),
+            sink,
+        )));
+        // Queued behind the stream, so it only runs once the stream gives up.
+        let queued = actor.send(EzdbMessage::Data(DataMessage::MutateRaw(
+            "INSERT INTO foo (x) VALUES (0)".to_owned(),
+            ValueEncoding::Typed,
+        )));
+        assert_eq!(
+            streamed.await.unwrap(),
+            Err(PersistenceError::DeadlineExceeded)
+        );
+        actix_rt::time::timeout(Duration::from_secs(1), queued)
+            .await
+            .expect("the queued job should run")
+            .unwrap()
+            .unwrap();
+        drop(chunks);
+    }
+
     #[actix_rt::test]
     async fn expensive_queries_can_be_interrupted() {
         let actor = CoreActor::new(SqlitePersistence::in_memory().unwrap()).start();
@@ -232,6 +1053,7 @@ mod test {
         // `foo` now has 1024 entries. `foo JOIN foo JOIN foo` has 2^30 entries, which is extremely expensive.
         let m0 = actor.send(EzdbMessage::Data(DataMessage::QueryRaw(
             "SELECT COUNT(1) FROM foo JOIN foo JOIN foo".to_owned(),
+            ValueEncoding::Typed,
         )));
         actix_rt::time::delay_for(Duration::from_millis(10)).await;
         let m1 = actor.send(EzdbMessage::Logistics(LogisticsMessage::Interrupt));
@@ -240,8 +1062,243 @@ mod test {
         assert_eq!(m0.await.unwrap(), Err(PersistenceError::Interrupted));
     }
 
+    #[actix_rt::test]
+    async fn one_job_can_be_cancelled() {
+        let actor = CoreActor::new(SqlitePersistence::in_memory().unwrap()).start();
+        mutate_raw(&actor, "CREATE TABLE foo (x INTEGER)").await;
+        mutate_raw(&actor, "INSERT INTO foo (x) VALUES (0)").await;
+        for _ in 0..10 {
+            mutate_raw(&actor, "INSERT INTO foo (x) SELECT x FROM foo").await;
+        }
+        let query = |sql: &str| {
+            actor.send(EzdbMessage::Data(DataMessage::QueryRaw(
+                sql.to_owned(),
+                ValueEncoding::Typed,
+            )))
+        };
+        let slow = query("SELECT COUNT(1) FROM foo JOIN foo JOIN foo");
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        let cancelled = query("SELECT 1 AS x");
+        let queued = query("SELECT 2 AS x");
+
+        let jobs = actor
+            .send(EzdbMessage::Logistics(LogisticsMessage::ListJobs))
+            .await
+            .unwrap()
+            .unwrap();
+        let jobs: Vec<serde_json::Value> = serde_json::from_str(&jobs).unwrap();
+        let summary: Vec<_> = jobs
+            .iter()
+            .map(|job| {
+                (
+                    job["kind"].clone(),
+                    job["detail"].clone(),
+                    job["running"].clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "queryRaw".into(),
+                    "SELECT COUNT(1) FROM foo JOIN foo JOIN foo".into(),
+                    true.into()
+                ),
+                ("queryRaw".into(), "SELECT 1 AS x".into(), false.into()),
+                ("queryRaw".into(), "SELECT 2 AS x".into(), false.into()),
+            ]
+        );
+
+        let cancel = |job: &serde_json::Value| {
+            let id = job["id"].as_u64().unwrap();
+            actor.send(EzdbMessage::Logistics(LogisticsMessage::Cancel(id)))
+        };
+        cancel(&jobs[1]).await.unwrap().unwrap();
+        cancel(&jobs[0]).await.unwrap().unwrap();
+        assert_eq!(slow.await.unwrap(), Err(PersistenceError::Interrupted));
+        assert_eq!(cancelled.await.unwrap(), Err(PersistenceError::Interrupted));
+        // Unlike `Interrupt`, cancelling leaves the other jobs alone.
+        assert_eq!(queued.await.unwrap().unwrap(), r#"[{"x":2}]"#);
+        assert_eq!(
+            cancel(&jobs[2]).await.unwrap(),
+            Err(PersistenceError::NoSuchJob(jobs[2]["id"].as_u64().unwrap()))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn shutdown_finishes_queued_requests() {
+        let actor = CoreActor::new(SqlitePersistence::in_memory().unwrap()).start();
+        let count = "WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n LIMIT 100000) SELECT COUNT(1) AS c FROM n";
+        let queued = actor.send(EzdbMessage::Data(DataMessage::QueryRaw(
+            count.to_owned(),
+            ValueEncoding::Typed,
+        )));
+        let shutdown = actor.send(EzdbMessage::Logistics(LogisticsMessage::Shutdown));
+        assert_eq!(shutdown.await.unwrap().unwrap(), "ok");
+        assert_eq!(queued.await.unwrap().unwrap(), r#"[{"c":100000}]"#);
+        assert!(!actor.connected());
+    }
+
+    #[actix_rt::test]
+    async fn least_recently_used_databases_are_closed() {
+        let dir = scratch_dir("lru");
+        let router = RoutingActor::with_config(
+            SqliteFactory::from_dir(dir.clone()),
+            RoutingConfig {
+                max_open: 2,
+                idle_timeout: Duration::from_secs(3600),
+                ..Default::default()
+            },
+        )
+        .start();
+        let a = router.send(db_addr("a")).await.unwrap().unwrap();
+        let b = router.send(db_addr("b")).await.unwrap().unwrap();
+        router.send(db_addr("a")).await.unwrap().unwrap();
+        router.send(db_addr("c")).await.unwrap().unwrap();
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        assert!(a.connected());
+        assert!(!b.connected());
+
+        let metrics = router.send(GetRoutingMetrics).await.unwrap();
+        assert_eq!(metrics.open, 2);
+        assert_eq!(metrics.opened, 3);
+        assert_eq!(metrics.capacity_evictions, 1);
+        assert_eq!(metrics.idle_evictions, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn closing_databases_are_not_opened_twice() {
+        let dir = scratch_dir("closing");
+        let router = RoutingActor::with_config(
+            SqliteFactory::from_dir(dir.clone()),
+            RoutingConfig {
+                max_open: 1,
+                idle_timeout: Duration::from_secs(3600),
+                ..Default::default()
+            },
+        )
+        .start();
+        let a = router.send(db_addr("a")).await.unwrap().unwrap();
+        let count = "WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n LIMIT 1000000) SELECT COUNT(1) AS c FROM n";
+        let mut slow = a.send(EzdbMessage::Data(DataMessage::QueryRaw(
+            count.to_owned(),
+            ValueEncoding::Typed,
+        )));
+        // Opening `b` closes `a`, which finishes the slow query first. Until it has, `a`
+        // can't be opened again.
+        router.send(db_addr("b")).await.unwrap().unwrap();
+        let reopened = router.send(db_addr("a")).await.unwrap().unwrap();
+        assert_eq!(
+            (&mut slow).now_or_never().unwrap().unwrap().unwrap(),
+            r#"[{"c":1000000}]"#
+        );
+        assert!(!a.connected());
+        mutate_raw(&reopened, "CREATE TABLE foo (x INTEGER)").await;
+
+        let metrics = router.send(GetRoutingMetrics).await.unwrap();
+        assert_eq!(metrics.open, 1);
+        assert_eq!(metrics.opened, 3);
+        assert_eq!(metrics.capacity_evictions, 2);
+        drop(reopened);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn idle_databases_are_closed() {
+        let dir = scratch_dir("idle");
+        let router = RoutingActor::with_config(
+            SqliteFactory::from_dir(dir.clone()),
+            RoutingConfig {
+                max_open: 2,
+                idle_timeout: Duration::from_millis(20),
+                ..Default::default()
+            },
+        )
+        .start();
+        let a = router.send(db_addr("a")).await.unwrap().unwrap();
+        actix_rt::time::delay_for(Duration::from_millis(60)).await;
+        assert!(!a.connected());
+        let metrics = router.send(GetRoutingMetrics).await.unwrap();
+        assert_eq!(metrics.open, 0);
+        assert_eq!(metrics.idle_evictions, 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn in_memory_databases_are_never_closed() {
+        let router = RoutingActor::with_config(
+            SqliteFactory::in_memory(),
+            RoutingConfig {
+                max_open: 1,
+                idle_timeout: Duration::from_millis(20),
+                ..Default::default()
+            },
+        )
+        .start();
+        let a = router.send(db_addr("a")).await.unwrap().unwrap();
+        router.send(db_addr("b")).await.unwrap().unwrap();
+        actix_rt::time::delay_for(Duration::from_millis(60)).await;
+        assert!(a.connected());
+        let metrics = router.send(GetRoutingMetrics).await.unwrap();
+        assert_eq!(metrics.open, 2);
+    }
+
+    #[actix_rt::test]
+    async fn queries_do_not_wait_for_writes() {
+        let dir = scratch_dir("readers");
+        let factory = SqliteFactory::from_dir(dir.clone());
+        let writer = factory.open(&db_addr("a")).unwrap();
+        let readers = factory.open_readers(&db_addr("a"), 1).unwrap();
+        let actor = CoreActor::with_readers(writer, readers).start();
+        mutate_raw(&actor, "CREATE TABLE foo (x INTEGER)").await;
+        mutate_raw(&actor, "INSERT INTO foo (x) VALUES (0)").await;
+        for _ in 0..10 {
+            mutate_raw(&actor, "INSERT INTO foo (x) SELECT x FROM foo").await;
+        }
+        let slow = actor.send(EzdbMessage::Data(DataMessage::QueryRaw(
+            "SELECT COUNT(1) FROM foo JOIN foo JOIN foo".to_owned(),
+            ValueEncoding::Typed,
+        )));
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        // The writer is free while the reader works on the slow query.
+        actix_rt::time::timeout(
+            Duration::from_secs(1),
+            mutate_raw(&actor, "INSERT INTO foo (x) VALUES (1)"),
+        )
+        .await
+        .unwrap();
+        // And interrupts reach the reader too.
+        let interrupt = actor.send(EzdbMessage::Logistics(LogisticsMessage::Interrupt));
+        assert_eq!(interrupt.await.unwrap().unwrap(), "ok");
+        assert_eq!(slow.await.unwrap(), Err(PersistenceError::Interrupted));
+
+        let count = actor.send(EzdbMessage::Data(DataMessage::QueryRaw(
+            "SELECT COUNT(1) AS c FROM foo".to_owned(),
+            ValueEncoding::Typed,
+        )));
+        assert_eq!(count.await.unwrap().unwrap(), r#"[{"c":1025}]"#);
+        drop(actor);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // A directory of its own for each test, since they run at the same time.
+    fn scratch_dir(test: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ezdb-core-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn db_addr(database_id: &str) -> DatabaseAddress {
+        DatabaseAddress {
+            project_id: "p".parse().unwrap(),
+            database_id: database_id.parse().unwrap(),
+        }
+    }
+
     async fn mutate_raw(actor: &Addr<CoreActor>, raw: &str) {
-        let req = DataMessage::MutateRaw(raw.to_owned());
+        let req = DataMessage::MutateRaw(raw.to_owned(), ValueEncoding::Typed);
         actor.send(EzdbMessage::Data(req)).await.unwrap().unwrap();
     }
 }
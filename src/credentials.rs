@@ -0,0 +1,547 @@
+use crate::persistence::{PersistenceError, PersistenceResult, SqliteFactory};
+use crate::rules::Caller;
+use crate::tokens::ProjectId;
+use actix::prelude::*;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::debug;
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CREDENTIALS_FILENAME: &str = "__ezdb_credentials__.sqlite";
+const KEY_PREFIX: &str = "ezdb";
+
+/// Who an admin request is acting as.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AdminIdentity {
+    /// The server operator, who may act on any project.
+    Root,
+    /// An admin key issued for a single project.
+    Key(String),
+}
+
+// How the identity is recorded as the author of a change: `root`, or the key's id.
+impl std::fmt::Display for AdminIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminIdentity::Root => f.write_str("root"),
+            AdminIdentity::Key(key_id) => f.write_str(key_id),
+        }
+    }
+}
+
+/// A freshly issued admin key. This is the only time the secret is ever visible.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IssuedKey {
+    pub key_id: String,
+    pub key: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminKey {
+    pub key_id: String,
+    pub created_at: i64,
+}
+
+/// How a project's end users prove who they are to the `/named` endpoints.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JwtConfig {
+    pub algorithm: JwtAlgorithm,
+    /// The shared secret for HS256, or the PEM-encoded public key for RS256.
+    #[serde(skip_serializing)]
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+}
+impl JwtAlgorithm {
+    fn as_str(self) -> &'static str {
+        match self {
+            JwtAlgorithm::HS256 => "HS256",
+            JwtAlgorithm::RS256 => "RS256",
+        }
+    }
+}
+impl std::str::FromStr for JwtAlgorithm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HS256" => Ok(JwtAlgorithm::HS256),
+            "RS256" => Ok(JwtAlgorithm::RS256),
+            _ => Err(format!("unsupported algorithm: {}", s)),
+        }
+    }
+}
+
+impl JwtConfig {
+    fn decoding_key(&self) -> PersistenceResult<DecodingKey<'_>> {
+        match self.algorithm {
+            JwtAlgorithm::HS256 => Ok(DecodingKey::from_secret(self.key.as_bytes())),
+            JwtAlgorithm::RS256 => DecodingKey::from_rsa_pem(self.key.as_bytes()).map_err(|e| {
+                PersistenceError::InvalidArgument(format!("invalid RS256 key: {}", e))
+            }),
+        }
+    }
+
+    fn validation(&self) -> Validation {
+        let mut validation = Validation::new(match self.algorithm {
+            JwtAlgorithm::HS256 => Algorithm::HS256,
+            JwtAlgorithm::RS256 => Algorithm::RS256,
+        });
+        validation.iss = self.issuer.clone();
+        if let Some(ref audience) = self.audience {
+            validation.set_audience(&[audience]);
+        }
+        validation
+    }
+
+    /// Verifies `token` and returns the caller it identifies. The `sub` claim doubles as
+    /// `uid` unless the token has its own `uid` claim.
+    pub fn authenticate(&self, token: &str) -> PersistenceResult<Caller> {
+        let data = jsonwebtoken::decode::<Map<String, Value>>(
+            token,
+            &self.decoding_key()?,
+            &self.validation(),
+        )
+        .map_err(|e| PersistenceError::Unauthenticated(format!("invalid token: {}", e)))?;
+        let mut claims = data.claims;
+        if let Some(sub) = claims.get("sub").cloned() {
+            claims.entry("uid").or_insert(sub);
+        }
+        Ok(Caller::authenticated(claims))
+    }
+}
+
+/// `CredentialStore` keeps track of the admin keys and end-user JWT configuration for
+/// every project.
+///
+/// Keys look like `ezdb_<key id>_<secret>`. Only a SHA-256 hash of the secret is stored.
+pub struct CredentialStore {
+    conn: Connection,
+    root_key_hash: String,
+}
+impl CredentialStore {
+    pub fn open(factory: &SqliteFactory, root_key: &str) -> PersistenceResult<CredentialStore> {
+        let conn = factory.connect(CREDENTIALS_FILENAME)?;
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS __ezdb_admin_keys__ (
+                key_id TEXT NOT NULL PRIMARY KEY,
+                project_id TEXT NOT NULL,
+                key_hash TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )
+        "#,
+            params![],
+        )?;
+        conn.execute(
+            r#"
+            CREATE TABLE IF NOT EXISTS __ezdb_jwt_configs__ (
+                project_id TEXT NOT NULL PRIMARY KEY,
+                algorithm TEXT NOT NULL,
+                key TEXT NOT NULL,
+                issuer TEXT,
+                audience TEXT
+            )
+        "#,
+            params![],
+        )?;
+        Ok(CredentialStore {
+            conn,
+            root_key_hash: hash_secret(root_key),
+        })
+    }
+
+    pub fn issue(&self, project_id: &ProjectId) -> PersistenceResult<IssuedKey> {
+        debug!("issuing admin key for {}", project_id);
+        let key_id = random_hex(8);
+        let secret = base64::encode_config(random_bytes(32), base64::URL_SAFE_NO_PAD);
+        self.conn.execute(
+            "INSERT INTO __ezdb_admin_keys__ (key_id, project_id, key_hash, created_at) VALUES (?, ?, ?, ?)",
+            params![key_id, project_id.to_string(), hash_secret(&secret), now()],
+        )?;
+        Ok(IssuedKey {
+            key: format!("{}_{}_{}", KEY_PREFIX, key_id, secret),
+            key_id,
+        })
+    }
+
+    pub fn revoke(&self, project_id: &ProjectId, key_id: &str) -> PersistenceResult<()> {
+        debug!("revoking admin key {} for {}", key_id, project_id);
+        let deleted = self.conn.execute(
+            "DELETE FROM __ezdb_admin_keys__ WHERE key_id = ? AND project_id = ?",
+            params![key_id, project_id.to_string()],
+        )?;
+        if deleted == 0 {
+            return Err(PersistenceError::NoSuchKey(key_id.to_owned()));
+        }
+        Ok(())
+    }
+
+    pub fn list(&self, project_id: &ProjectId) -> PersistenceResult<Vec<AdminKey>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT key_id, created_at FROM __ezdb_admin_keys__ WHERE project_id = ? ORDER BY created_at",
+        )?;
+        let keys = stmt
+            .query_map(params![project_id.to_string()], |row| {
+                Ok(AdminKey {
+                    key_id: row.get(0)?,
+                    created_at: row.get(1)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(keys)
+    }
+
+    /// Checks `token` against the root key and, if `project_id` is given, against that
+    /// project's admin keys. A key issued for one project is never valid for another.
+    pub fn verify(
+        &self,
+        project_id: Option<&ProjectId>,
+        token: &str,
+    ) -> PersistenceResult<Option<AdminIdentity>> {
+        if hash_secret(token) == self.root_key_hash {
+            return Ok(Some(AdminIdentity::Root));
+        }
+        let project_id = match project_id {
+            Some(project_id) => project_id,
+            None => return Ok(None),
+        };
+        let (key_id, secret) = match parse_key(token) {
+            Some(parsed) => parsed,
+            None => return Ok(None),
+        };
+        let key_hash: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT key_hash FROM __ezdb_admin_keys__ WHERE key_id = ? AND project_id = ?",
+                params![key_id, project_id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(match key_hash {
+            Some(key_hash) if key_hash == hash_secret(secret) => {
+                Some(AdminIdentity::Key(key_id.to_owned()))
+            }
+            _ => None,
+        })
+    }
+
+    pub fn set_jwt_config(
+        &self,
+        project_id: &ProjectId,
+        config: JwtConfig,
+    ) -> PersistenceResult<()> {
+        debug!(
+            "setting {} jwt config for {}",
+            config.algorithm.as_str(),
+            project_id
+        );
+        config.decoding_key()?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO __ezdb_jwt_configs__ (project_id, algorithm, key, issuer, audience) VALUES (?, ?, ?, ?, ?)",
+            params![
+                project_id.to_string(),
+                config.algorithm.as_str(),
+                config.key,
+                config.issuer,
+                config.audience
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn jwt_config(&self, project_id: &ProjectId) -> PersistenceResult<Option<JwtConfig>> {
+        let config = self
+            .conn
+            .query_row(
+                "SELECT algorithm, key, issuer, audience FROM __ezdb_jwt_configs__ WHERE project_id = ?",
+                params![project_id.to_string()],
+                |row| {
+                    let algorithm: String = row.get(0)?;
+                    Ok((algorithm, row.get(1)?, row.get(2)?, row.get(3)?))
+                },
+            )
+            .optional()?;
+        match config {
+            None => Ok(None),
+            Some((algorithm, key, issuer, audience)) => Ok(Some(JwtConfig {
+                algorithm: algorithm.parse().map_err(PersistenceError::Unknown)?,
+                key,
+                issuer,
+                audience,
+            })),
+        }
+    }
+
+    pub fn delete_jwt_config(&self, project_id: &ProjectId) -> PersistenceResult<()> {
+        self.conn.execute(
+            "DELETE FROM __ezdb_jwt_configs__ WHERE project_id = ?",
+            params![project_id.to_string()],
+        )?;
+        Ok(())
+    }
+
+    pub fn authenticate(&self, project_id: &ProjectId, token: &str) -> PersistenceResult<Caller> {
+        match self.jwt_config(project_id)? {
+            Some(config) => config.authenticate(token),
+            None => Err(PersistenceError::Unauthenticated(format!(
+                "project {} does not accept end-user tokens",
+                project_id
+            ))),
+        }
+    }
+}
+
+/// Generates a random key suitable for use as the server's root key.
+pub fn generate_root_key() -> String {
+    base64::encode_config(random_bytes(32), base64::URL_SAFE_NO_PAD)
+}
+
+fn parse_key(token: &str) -> Option<(&str, &str)> {
+    let mut parts = token.splitn(3, '_');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(KEY_PREFIX), Some(key_id), Some(secret)) => Some((key_id, secret)),
+        _ => None,
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex(&Sha256::digest(secret.as_bytes()))
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    rand::thread_rng().fill_bytes(&mut buf);
+    buf
+}
+
+fn random_hex(len: usize) -> String {
+    hex(&random_bytes(len))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// `CredentialActor` serializes access to the `CredentialStore`.
+pub struct CredentialActor {
+    store: CredentialStore,
+}
+impl CredentialActor {
+    pub fn new(store: CredentialStore) -> CredentialActor {
+        CredentialActor { store }
+    }
+}
+
+impl Actor for CredentialActor {
+    type Context = Context<Self>;
+}
+
+pub struct IssueAdminKey(pub ProjectId);
+impl Message for IssueAdminKey {
+    type Result = PersistenceResult<IssuedKey>;
+}
+impl Handler<IssueAdminKey> for CredentialActor {
+    type Result = PersistenceResult<IssuedKey>;
+
+    fn handle(&mut self, msg: IssueAdminKey, _ctx: &mut Context<Self>) -> Self::Result {
+        self.store.issue(&msg.0)
+    }
+}
+
+pub struct RevokeAdminKey(pub ProjectId, pub String);
+impl Message for RevokeAdminKey {
+    type Result = PersistenceResult<()>;
+}
+impl Handler<RevokeAdminKey> for CredentialActor {
+    type Result = PersistenceResult<()>;
+
+    fn handle(&mut self, msg: RevokeAdminKey, _ctx: &mut Context<Self>) -> Self::Result {
+        self.store.revoke(&msg.0, &msg.1)
+    }
+}
+
+pub struct ListAdminKeys(pub ProjectId);
+impl Message for ListAdminKeys {
+    type Result = PersistenceResult<Vec<AdminKey>>;
+}
+impl Handler<ListAdminKeys> for CredentialActor {
+    type Result = PersistenceResult<Vec<AdminKey>>;
+
+    fn handle(&mut self, msg: ListAdminKeys, _ctx: &mut Context<Self>) -> Self::Result {
+        self.store.list(&msg.0)
+    }
+}
+
+pub struct VerifyAdminKey {
+    pub project_id: Option<ProjectId>,
+    pub token: String,
+}
+impl Message for VerifyAdminKey {
+    type Result = PersistenceResult<Option<AdminIdentity>>;
+}
+impl Handler<VerifyAdminKey> for CredentialActor {
+    type Result = PersistenceResult<Option<AdminIdentity>>;
+
+    fn handle(&mut self, msg: VerifyAdminKey, _ctx: &mut Context<Self>) -> Self::Result {
+        self.store.verify(msg.project_id.as_ref(), &msg.token)
+    }
+}
+
+pub struct SetJwtConfig(pub ProjectId, pub JwtConfig);
+impl Message for SetJwtConfig {
+    type Result = PersistenceResult<()>;
+}
+impl Handler<SetJwtConfig> for CredentialActor {
+    type Result = PersistenceResult<()>;
+
+    fn handle(&mut self, msg: SetJwtConfig, _ctx: &mut Context<Self>) -> Self::Result {
+        self.store.set_jwt_config(&msg.0, msg.1)
+    }
+}
+
+pub struct GetJwtConfig(pub ProjectId);
+impl Message for GetJwtConfig {
+    type Result = PersistenceResult<Option<JwtConfig>>;
+}
+impl Handler<GetJwtConfig> for CredentialActor {
+    type Result = PersistenceResult<Option<JwtConfig>>;
+
+    fn handle(&mut self, msg: GetJwtConfig, _ctx: &mut Context<Self>) -> Self::Result {
+        self.store.jwt_config(&msg.0)
+    }
+}
+
+pub struct DeleteJwtConfig(pub ProjectId);
+impl Message for DeleteJwtConfig {
+    type Result = PersistenceResult<()>;
+}
+impl Handler<DeleteJwtConfig> for CredentialActor {
+    type Result = PersistenceResult<()>;
+
+    fn handle(&mut self, msg: DeleteJwtConfig, _ctx: &mut Context<Self>) -> Self::Result {
+        self.store.delete_jwt_config(&msg.0)
+    }
+}
+
+pub struct AuthenticateEndUser {
+    pub project_id: ProjectId,
+    pub token: String,
+}
+impl Message for AuthenticateEndUser {
+    type Result = PersistenceResult<Caller>;
+}
+impl Handler<AuthenticateEndUser> for CredentialActor {
+    type Result = PersistenceResult<Caller>;
+
+    fn handle(&mut self, msg: AuthenticateEndUser, _ctx: &mut Context<Self>) -> Self::Result {
+        self.store.authenticate(&msg.project_id, &msg.token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AdminIdentity, CredentialStore, JwtAlgorithm, JwtConfig};
+    use crate::persistence::{PersistenceError, SqliteFactory};
+    use crate::tokens::ProjectId;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn keys_are_scoped_to_their_project() {
+        let store = CredentialStore::open(&SqliteFactory::in_memory(), "root").unwrap();
+        let alpha: ProjectId = "alpha".parse().unwrap();
+        let beta: ProjectId = "beta".parse().unwrap();
+        let issued = store.issue(&alpha).unwrap();
+
+        assert_eq!(
+            store.verify(Some(&alpha), &issued.key).unwrap(),
+            Some(AdminIdentity::Key(issued.key_id.clone()))
+        );
+        assert_eq!(store.verify(Some(&beta), &issued.key).unwrap(), None);
+        assert_eq!(store.verify(None, &issued.key).unwrap(), None);
+        assert_eq!(
+            store.verify(Some(&beta), "root").unwrap(),
+            Some(AdminIdentity::Root)
+        );
+        assert_eq!(store.verify(Some(&alpha), "admin").unwrap(), None);
+    }
+
+    #[test]
+    fn revoked_keys_are_rejected() {
+        let store = CredentialStore::open(&SqliteFactory::in_memory(), "root").unwrap();
+        let alpha: ProjectId = "alpha".parse().unwrap();
+        let issued = store.issue(&alpha).unwrap();
+        store.revoke(&alpha, &issued.key_id).unwrap();
+
+        assert_eq!(store.verify(Some(&alpha), &issued.key).unwrap(), None);
+        assert!(store.list(&alpha).unwrap().is_empty());
+        assert_eq!(
+            store.revoke(&alpha, &issued.key_id),
+            Err(PersistenceError::NoSuchKey(issued.key_id))
+        );
+    }
+
+    #[test]
+    fn end_users_authenticate_with_project_jwts() {
+        let store = CredentialStore::open(&SqliteFactory::in_memory(), "root").unwrap();
+        let alpha: ProjectId = "alpha".parse().unwrap();
+        let beta: ProjectId = "beta".parse().unwrap();
+        let config = JwtConfig {
+            algorithm: JwtAlgorithm::HS256,
+            key: "secret".to_owned(),
+            issuer: None,
+            audience: None,
+        };
+        store.set_jwt_config(&alpha, config).unwrap();
+
+        let claims = json!({"sub": "alice", "exp": 10_000_000_000u64});
+        let token = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let caller = store.authenticate(&alpha, &token).unwrap();
+        assert_eq!(
+            caller.claims().unwrap().get("uid"),
+            Some(&Value::from("alice"))
+        );
+        assert!(matches!(
+            store.authenticate(&beta, &token),
+            Err(PersistenceError::Unauthenticated(_))
+        ));
+
+        let forged = encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"guess"),
+        )
+        .unwrap();
+        assert!(matches!(
+            store.authenticate(&alpha, &forged),
+            Err(PersistenceError::Unauthenticated(_))
+        ));
+    }
+}
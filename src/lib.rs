@@ -1,5 +1,9 @@
 pub mod analyzer;
+pub mod codegen;
 pub mod core;
+pub mod credentials;
 pub mod persistence;
+pub mod rules;
 pub mod server;
 pub mod tokens;
+pub mod values;
@@ -1,7 +1,14 @@
-use crate::core::Policy;
+use crate::analyzer::{Schema, TemplateError};
+use crate::core::{BatchStep, Migration, PageRequest, Policy, PolicyChange};
+use crate::rules::Caller;
+use crate::values::ValueEncoding;
 use rusqlite::InterruptHandle;
+use serde::Serialize;
 use serde_json::Value;
 use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 pub type PersistenceResult<T> = ::std::result::Result<T, PersistenceError>;
 
@@ -9,44 +16,392 @@ pub type PersistenceResult<T> = ::std::result::Result<T, PersistenceError>;
 pub enum PersistenceError {
     Unknown(String),
     NoSuchQuery(String),
+    NoSuchKey(String),
+    /// A job id that doesn't belong to a running or queued job.
+    NoSuchJob(u64),
+    /// A policy revision that was never made.
+    NoSuchRevision(u64),
+    PermissionDenied(String),
+    Unauthenticated(String),
+    InvalidArgument(String),
+    /// A request that only accepts formats rows can't be written in, given its `Accept`
+    /// header and the value encoding it asks for.
+    NotAcceptable {
+        accept: String,
+        encoding: ValueEncoding,
+    },
+    /// The params of a request don't match the ones its template binds.
+    InvalidParams {
+        missing: Vec<String>,
+        unexpected: Vec<String>,
+    },
+    /// A param's value can't be bound to its template.
+    InvalidParamValue {
+        name: String,
+        message: String,
+    },
+    InvalidPolicy(Vec<TemplateError>),
+    /// SQL that SQLite can't parse.
+    SyntaxError(String),
+    /// SQL that refers to tables, columns or functions that don't exist, or tries to
+    /// create ones that already do.
+    SchemaError(String),
+    /// A statement that would violate a `UNIQUE`, `NOT NULL`, `CHECK` or foreign key
+    /// constraint.
+    ConstraintViolation(String),
+    /// A query or a mutation's `RETURNING` clause returned more rows than the server
+    /// allows in one response.
+    TooManyRows(usize),
+    /// One step of a batch failed, so the whole batch was rolled back.
+    BatchFailed {
+        step: usize,
+        cause: Box<PersistenceError>,
+    },
     Busy,
     Interrupted,
+    /// A statement ran for longer than its template's timeout and was aborted, or a
+    /// streamed query's rows weren't read in time.
+    DeadlineExceeded,
+    /// A policy change was made against a version of the policy that's since changed.
+    VersionMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    /// A migration that's already been applied is missing from the list being applied,
+    /// or has been edited since.
+    MigrationConflict {
+        name: String,
+        message: String,
+    },
+}
+
+impl PersistenceError {
+    /// Attributes this error to step `step` of a batch.
+    pub fn in_step(self, step: usize) -> PersistenceError {
+        PersistenceError::BatchFailed {
+            step,
+            cause: Box::new(self),
+        }
+    }
 }
 
 impl From<rusqlite::Error> for PersistenceError {
     fn from(err: rusqlite::Error) -> PersistenceError {
-        if let rusqlite::Error::SqliteFailure(e, _) = err {
-            if e.code == rusqlite::ErrorCode::OperationInterrupted {
-                return PersistenceError::Interrupted;
+        use rusqlite::ErrorCode;
+        let (code, msg) = match err {
+            rusqlite::Error::SqliteFailure(e, msg) => {
+                let msg = msg.unwrap_or_else(|| e.to_string());
+                (e.code, msg)
             }
+            rusqlite::Error::SqlInputError { error, msg, .. } => (error.code, msg),
+            rusqlite::Error::ExecuteReturnedResults
+            | rusqlite::Error::MultipleStatement
+            | rusqlite::Error::InvalidParameterName(_)
+            | rusqlite::Error::InvalidParameterCount(_, _) => {
+                return PersistenceError::InvalidArgument(err.to_string())
+            }
+            err => return PersistenceError::Unknown(err.to_string()),
+        };
+        match code {
+            ErrorCode::OperationInterrupted => PersistenceError::Interrupted,
+            ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => PersistenceError::Busy,
+            ErrorCode::ConstraintViolation => PersistenceError::ConstraintViolation(msg),
+            ErrorCode::PermissionDenied
+            | ErrorCode::ReadOnly
+            | ErrorCode::AuthorizationForStatementDenied => PersistenceError::PermissionDenied(msg),
+            // A string or blob longer than the connection's limit allows.
+            ErrorCode::TooBig => PersistenceError::InvalidArgument(msg),
+            // SQLite reports most problems with a statement as a generic SQLITE_ERROR, so
+            // the message is all there is to go on.
+            ErrorCode::Unknown => classify_statement_error(msg),
+            _ => PersistenceError::Unknown(msg),
         }
-        PersistenceError::Unknown(format!("{:?}", err))
+    }
+}
+
+fn classify_statement_error(msg: String) -> PersistenceError {
+    if msg.ends_with("syntax error")
+        || msg.starts_with("unrecognized token")
+        || msg == "incomplete input"
+    {
+        PersistenceError::SyntaxError(msg)
+    } else if msg.starts_with("no such ")
+        || msg.contains("already exists")
+        || msg.contains("has no column named")
+    {
+        PersistenceError::SchemaError(msg)
+    } else {
+        PersistenceError::InvalidArgument(msg)
     }
 }
 
 impl From<actix::MailboxError> for PersistenceError {
     fn from(err: actix::MailboxError) -> PersistenceError {
-        PersistenceError::Unknown(format!("{:?}", err))
+        match err {
+            // The database was closed while the request was in flight, and retrying will
+            // reopen it.
+            actix::MailboxError::Closed => PersistenceError::Busy,
+            err => PersistenceError::Unknown(format!("{:?}", err)),
+        }
+    }
+}
+
+/// What a mutation did.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MutationResult {
+    #[serde(flatten)]
+    pub counts: MutationCounts,
+    /// The rows produced by a `RETURNING` clause, in the same shape as query results.
+    pub rows: Value,
+}
+
+/// What a mutation did, apart from the rows it returned.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MutationCounts {
+    /// The number of rows inserted, updated or deleted, not counting triggers.
+    pub changes: u64,
+    /// The rowid of the most recent successful insert on this connection, which may
+    /// predate this mutation.
+    pub last_insert_rowid: i64,
+}
+
+/// One page of a named query's results.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryPage {
+    pub rows: Value,
+    /// Pass this as the `pageToken` of the next request to get the next page. It's
+    /// missing from the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_page_token: Option<String>,
+}
+
+/// A database's policy, and its version, which goes up by one with every change.
+#[derive(Debug, Serialize)]
+pub struct VersionedPolicy {
+    pub version: u64,
+    #[serde(flatten)]
+    pub policy: Policy,
+}
+
+/// One accepted version of a policy. Revisions are never changed once they're made, and
+/// are numbered by the policy version they created.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyRevision {
+    pub revision: u64,
+    /// When it was made, in seconds since the Unix epoch.
+    pub created_at: i64,
+    /// Who made it: the id of an admin key, or `root`. Changes made other than through
+    /// the API don't have one.
+    pub author: Option<String>,
+    /// Missing from listings of the history.
+    #[serde(flatten)]
+    pub policy: Option<Policy>,
+}
+
+/// A migration that's been applied to a database.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppliedMigration {
+    pub name: String,
+    /// The SHA-256 of its SQL, in hex.
+    pub checksum: String,
+    /// When it was applied, in seconds since the Unix epoch.
+    pub applied_at: i64,
+}
+
+/// What applying a list of migrations did.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationReport {
+    /// The migrations that were applied, or that would have been on a dry run, in order.
+    pub applied: Vec<String>,
+    pub dry_run: bool,
+}
+
+/// How a query's rows are written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowFormat {
+    /// A JSON array of objects, one per row.
+    Json,
+    /// One JSON object per line.
+    Ndjson,
+    /// `{"columns": [...], "rows": [[...]]}`, which only names each column once.
+    Columns,
+    /// CSV with a header row, as in RFC 4180. Blobs, and text that isn't valid UTF-8,
+    /// are base64-encoded.
+    Csv,
+    /// The same shape as `Columns`, in CBOR.
+    Cbor,
+}
+
+impl RowFormat {
+    pub const ALL: [RowFormat; 5] = [
+        RowFormat::Json,
+        RowFormat::Ndjson,
+        RowFormat::Columns,
+        RowFormat::Csv,
+        RowFormat::Cbor,
+    ];
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            RowFormat::Json => "application/json",
+            RowFormat::Ndjson => "application/x-ndjson",
+            RowFormat::Columns => "application/vnd.ezdb.columns+json",
+            RowFormat::Csv => "text/csv",
+            RowFormat::Cbor => "application/cbor",
+        }
+    }
+
+    /// Whether values can be written in this format as `encoding` asks. CSV fields are
+    /// all text, so it can't keep blobs apart from text the way the typed encoding does.
+    pub fn can_encode(&self, encoding: ValueEncoding) -> bool {
+        !(*self == RowFormat::Csv && encoding == ValueEncoding::Typed)
     }
 }
 
 pub trait Persistence: Send {
+    /// Runs a named query on behalf of `caller`, if its rule allows them to. The rule
+    /// is read along with the template, so it's always the one that goes with the SQL
+    /// that runs. The same goes for the other named methods. Values in the rows are
+    /// written in `encoding`, as they are by every method that returns rows.
     fn query_named(
         &self,
+        caller: &Caller,
         name: String,
         params: BTreeMap<String, Value>,
+        encoding: ValueEncoding,
     ) -> PersistenceResult<Value>;
-    fn mutate_named(&self, name: String, params: BTreeMap<String, Value>) -> PersistenceResult<()>;
-    fn query_raw(&self, query: String) -> PersistenceResult<Value>;
-    fn mutate_raw(&self, stmt: String) -> PersistenceResult<()>;
-    fn fetch_policy(&self) -> PersistenceResult<Policy>;
-    fn set_policy(&self, policy: Policy) -> PersistenceResult<()>;
+    fn query_page(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        page: PageRequest,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<QueryPage>;
+    /// Like `query_named`, but writes each row to `out` in `format` as soon as it's read,
+    /// instead of collecting them. Nothing is written if the query fails before its
+    /// first row. Given a `page`, it only writes the rows `query_page` would return, and
+    /// returns the token for the next page, if there is one.
+    #[allow(clippy::too_many_arguments)]
+    fn stream_named(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        page: Option<PageRequest>,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<Option<String>>;
+    /// Runs a named mutation, failing if its `RETURNING` clause returns more rows than
+    /// a query could.
+    fn mutate_named(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<MutationResult>;
+    /// Like `mutate_named`, but writes the rows of a `RETURNING` clause to `out` in
+    /// `format`, the same way `stream_named` does.
+    fn stream_mutation_named(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<MutationCounts>;
+    /// Runs every step in one transaction, returning each step's result. If any step
+    /// fails, none of them take effect.
+    fn batch(
+        &self,
+        caller: &Caller,
+        steps: Vec<BatchStep>,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<Vec<Value>>;
+    fn query_raw(&self, query: String, encoding: ValueEncoding) -> PersistenceResult<Value>;
+    /// Like `query_raw`, but streams the rows like `stream_named`.
+    fn stream_raw(
+        &self,
+        query: String,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<()>;
+    /// Runs a statement that may modify the database. Like `mutate_named`, it fails if
+    /// it returns too many rows, and then nothing it did takes effect.
+    fn mutate_raw(
+        &self,
+        stmt: String,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<MutationResult>;
+    /// Like `mutate_raw`, but writes rows like `stream_mutation_named`.
+    fn stream_mutation_raw(
+        &self,
+        stmt: String,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<MutationCounts>;
+    fn fetch_policy(&self) -> PersistenceResult<VersionedPolicy>;
+    /// Makes one change to the policy, recording the result as a new revision by
+    /// `author`, and returning its version. If `if_version` is given, the change is only
+    /// made if that's still the policy's version.
+    fn update_policy(
+        &self,
+        change: PolicyChange,
+        if_version: Option<u64>,
+        author: Option<String>,
+    ) -> PersistenceResult<u64>;
+    fn set_policy(&self, policy: Policy) -> PersistenceResult<()> {
+        self.update_policy(PolicyChange::Replace(policy), None, None)
+            .map(|_| ())
+    }
+    /// Lists every revision of the policy, oldest first, without their templates.
+    fn policy_history(&self) -> PersistenceResult<Vec<PolicyRevision>>;
+    fn fetch_policy_revision(&self, revision: u64) -> PersistenceResult<PolicyRevision>;
+    fn fetch_schema(&self) -> PersistenceResult<Schema>;
+    fn list_migrations(&self) -> PersistenceResult<Vec<AppliedMigration>>;
+    /// Applies the migrations in `migrations` that haven't been yet, in one transaction.
+    /// The ones that have must come first, in the order they were applied. A dry run
+    /// rolls the transaction back, after checking that the policy still fits the schema.
+    fn apply_migrations(
+        &self,
+        migrations: Vec<Migration>,
+        dry_run: bool,
+    ) -> PersistenceResult<MigrationReport>;
     fn get_interrupt_handle(&self) -> InterruptHandle;
+    /// A flag that makes whatever the connection is running fail with `Interrupted`
+    /// once it's set, whether it's in the middle of a statement or between two. Unlike
+    /// an interrupt, it stays set until it's cleared, so a job can't miss it.
+    fn get_cancel_flag(&self) -> Arc<AtomicBool>;
 }
 
+mod format;
 mod sqlite;
 mod timed;
 
+impl From<std::io::Error> for PersistenceError {
+    fn from(err: std::io::Error) -> PersistenceError {
+        match err.kind() {
+            // Whoever was reading a streamed query's rows went away.
+            std::io::ErrorKind::BrokenPipe => PersistenceError::Interrupted,
+            // Or didn't read them fast enough.
+            std::io::ErrorKind::TimedOut => PersistenceError::DeadlineExceeded,
+            _ => PersistenceError::Unknown(err.to_string()),
+        }
+    }
+}
+
 pub use sqlite::SqliteFactory;
+pub use sqlite::SqliteLimits;
 pub use sqlite::SqlitePersistence;
 pub use timed::Timed;
@@ -0,0 +1,338 @@
+use crate::persistence::{PersistenceError, PersistenceResult, RowFormat};
+use crate::values::{self, ValueEncoding};
+use rusqlite::types::ValueRef;
+use rusqlite::{Params, Row, Statement};
+use serde::ser::{Error as _, SerializeMap, SerializeSeq, Serializer};
+use serde::Serialize;
+use serde_json::Value;
+use std::io::Write;
+
+// CBOR's markers for the start and end of an array whose length isn't known up front.
+const CBOR_ARRAY_START: u8 = 0x9f;
+const CBOR_BREAK: u8 = 0xff;
+const CBOR_MAP_OF_TWO: u8 = 0xa2;
+
+/// Writes up to `limit` rows of `stmt` to `out` in `format`, after skipping the first
+/// `offset`, and reports whether there were more after those. The first row is read
+/// before anything is written, so that a statement that fails right away doesn't leave
+/// half a response. `encoding` only matters to the JSON formats. CBOR has types of its
+/// own for everything SQLite stores, so its values are always exact. CSV has no types,
+/// so it can't be written in the typed encoding at all.
+pub(super) fn write_rows<P: Params>(
+    stmt: &mut Statement,
+    params: P,
+    format: RowFormat,
+    encoding: ValueEncoding,
+    offset: usize,
+    limit: usize,
+    out: &mut dyn Write,
+) -> PersistenceResult<bool> {
+    let tagged = encoding == ValueEncoding::Typed;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let mut rows = stmt.query(params)?;
+    let mut row = rows.next()?;
+    for _ in 0..offset {
+        if row.is_none() {
+            break;
+        }
+        row = rows.next()?;
+    }
+    write_start(format, &columns, out)?;
+    let mut written = 0;
+    while let Some(r) = row {
+        if written == limit {
+            break;
+        }
+        write_row(format, tagged, written == 0, r, out)?;
+        written += 1;
+        row = rows.next()?;
+    }
+    let more = row.is_some();
+    write_end(format, out)?;
+    out.flush()?;
+    Ok(more)
+}
+
+/// Reads `row` into a JSON object of its columns, in `encoding`, the same way the JSON
+/// formats write it.
+pub(super) fn row_value(row: &Row, encoding: ValueEncoding) -> PersistenceResult<Value> {
+    serde_json::to_value(RowRef(row, encoding == ValueEncoding::Typed)).map_err(json_error)
+}
+
+fn write_start(
+    format: RowFormat,
+    columns: &[String],
+    out: &mut dyn Write,
+) -> PersistenceResult<()> {
+    match format {
+        RowFormat::Json => out.write_all(b"[")?,
+        RowFormat::Ndjson => {}
+        RowFormat::Columns => {
+            out.write_all(b"{\"columns\":")?;
+            serde_json::to_writer(&mut *out, columns).map_err(json_error)?;
+            out.write_all(b",\"rows\":[")?;
+        }
+        RowFormat::Csv => {
+            let header: Vec<_> = columns
+                .iter()
+                .map(|c| ValueRef::Text(c.as_bytes()))
+                .collect();
+            write_csv_record(&header, out)?;
+        }
+        RowFormat::Cbor => {
+            out.write_all(&[CBOR_MAP_OF_TWO])?;
+            ciborium::ser::into_writer("columns", &mut *out).map_err(cbor_error)?;
+            ciborium::ser::into_writer(columns, &mut *out).map_err(cbor_error)?;
+            ciborium::ser::into_writer("rows", &mut *out).map_err(cbor_error)?;
+            out.write_all(&[CBOR_ARRAY_START])?;
+        }
+    }
+    Ok(())
+}
+
+fn write_row(
+    format: RowFormat,
+    tagged: bool,
+    first: bool,
+    row: &Row,
+    out: &mut dyn Write,
+) -> PersistenceResult<()> {
+    match format {
+        RowFormat::Json => {
+            if !first {
+                out.write_all(b",")?;
+            }
+            serde_json::to_writer(&mut *out, &RowRef(row, tagged)).map_err(json_error)?;
+        }
+        RowFormat::Ndjson => {
+            serde_json::to_writer(&mut *out, &RowRef(row, tagged)).map_err(json_error)?;
+            out.write_all(b"\n")?;
+        }
+        RowFormat::Columns => {
+            if !first {
+                out.write_all(b",")?;
+            }
+            serde_json::to_writer(&mut *out, &RowValuesRef(row, tagged)).map_err(json_error)?;
+        }
+        RowFormat::Csv => {
+            let values: Vec<_> = (0..row.as_ref().column_count())
+                .map(|i| row.get_ref_unwrap(i))
+                .collect();
+            write_csv_record(&values, out)?;
+        }
+        RowFormat::Cbor => {
+            // Untagged, since CBOR's own bytes, integers and floats already tell every
+            // value apart, as tagging does in JSON.
+            ciborium::ser::into_writer(&RowValuesRef(row, false), &mut *out).map_err(cbor_error)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_end(format: RowFormat, out: &mut dyn Write) -> PersistenceResult<()> {
+    match format {
+        RowFormat::Json => out.write_all(b"]")?,
+        RowFormat::Columns => out.write_all(b"]}")?,
+        RowFormat::Cbor => out.write_all(&[CBOR_BREAK])?,
+        RowFormat::Ndjson | RowFormat::Csv => {}
+    }
+    Ok(())
+}
+
+// NULL is an empty field. Fields are only quoted when they need to be. Text that isn't
+// valid UTF-8 is written as a blob, as it's returned everywhere else.
+fn write_csv_record(values: &[ValueRef], out: &mut dyn Write) -> PersistenceResult<()> {
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        match *value {
+            ValueRef::Null => {}
+            ValueRef::Integer(n) => write!(out, "{}", n)?,
+            ValueRef::Real(f) => write!(out, "{}", f)?,
+            ValueRef::Text(t) if std::str::from_utf8(t).is_err() => {
+                out.write_all(base64::encode(t).as_bytes())?
+            }
+            ValueRef::Text(t) => {
+                if t.iter().any(|b| matches!(b, b',' | b'"' | b'\r' | b'\n')) {
+                    out.write_all(b"\"")?;
+                    for (j, part) in t.split(|b| *b == b'"').enumerate() {
+                        if j > 0 {
+                            out.write_all(b"\"\"")?;
+                        }
+                        out.write_all(part)?;
+                    }
+                    out.write_all(b"\"")?;
+                } else {
+                    out.write_all(t)?;
+                }
+            }
+            ValueRef::Blob(b) => out.write_all(base64::encode(b).as_bytes())?,
+        }
+    }
+    out.write_all(b"\r\n")?;
+    Ok(())
+}
+
+fn json_error(err: serde_json::Error) -> PersistenceError {
+    if err.is_io() {
+        std::io::Error::from(err).into()
+    } else {
+        PersistenceError::Unknown(err.to_string())
+    }
+}
+
+fn cbor_error(err: ciborium::ser::Error<std::io::Error>) -> PersistenceError {
+    match err {
+        ciborium::ser::Error::Io(err) => err.into(),
+        ciborium::ser::Error::Value(msg) => PersistenceError::Unknown(msg),
+    }
+}
+
+// Serializes a row as an object of its columns, straight from SQLite's buffers.
+struct RowRef<'a, 'stmt>(&'a Row<'stmt>, bool);
+
+impl Serialize for RowRef<'_, '_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let stmt = self.0.as_ref();
+        let mut map = serializer.serialize_map(Some(stmt.column_count()))?;
+        for i in 0..stmt.column_count() {
+            let name = stmt.column_name(i).map_err(S::Error::custom)?;
+            map.serialize_entry(name, &ValueRefSer(self.0.get_ref_unwrap(i), self.1))?;
+        }
+        map.end()
+    }
+}
+
+// Serializes a row as an array of its values, in column order.
+struct RowValuesRef<'a, 'stmt>(&'a Row<'stmt>, bool);
+
+impl Serialize for RowValuesRef<'_, '_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let count = self.0.as_ref().column_count();
+        let mut seq = serializer.serialize_seq(Some(count))?;
+        for i in 0..count {
+            seq.serialize_element(&ValueRefSer(self.0.get_ref_unwrap(i), self.1))?;
+        }
+        seq.end()
+    }
+}
+
+// Serializes a value the same way as the `MyValue` it would be read into, tagging the
+// ones plain JSON can't express if the second field is set.
+struct ValueRefSer<'a>(ValueRef<'a>, bool);
+
+impl Serialize for ValueRefSer<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            ValueRef::Null => serializer.serialize_none(),
+            ValueRef::Integer(i) if self.1 => values::integer(i).serialize(serializer),
+            ValueRef::Integer(i) => serializer.serialize_i64(i),
+            ValueRef::Real(f) if self.1 => values::real(f).serialize(serializer),
+            ValueRef::Real(f) => serializer.serialize_f64(f),
+            ValueRef::Text(t) => match std::str::from_utf8(t) {
+                Ok(text) => serializer.serialize_str(text),
+                Err(_) => ValueRefSer(ValueRef::Blob(t), self.1).serialize(serializer),
+            },
+            ValueRef::Blob(b) if self.1 => values::blob(b).serialize(serializer),
+            ValueRef::Blob(b) => serializer.serialize_bytes(b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::write_rows;
+    use crate::persistence::RowFormat;
+    use crate::values::ValueEncoding;
+    use ciborium::value::Value;
+    use rusqlite::Connection;
+
+    fn write(conn: &Connection, sql: &str, format: RowFormat) -> Vec<u8> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        let mut out = Vec::new();
+        write_rows(
+            &mut stmt,
+            [],
+            format,
+            ValueEncoding::Plain,
+            0,
+            usize::MAX,
+            &mut out,
+        )
+        .unwrap();
+        out
+    }
+
+    fn people() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE person (id INTEGER, name TEXT, score REAL, photo BLOB);
+            INSERT INTO person VALUES (1, 'alice', 1.5, x'0102'), (2, 'bob "the, builder"', NULL, NULL);
+            "#,
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn columns_are_only_named_once() {
+        let out = write(&people(), "SELECT id, name FROM person", RowFormat::Columns);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"{"columns":["id","name"],"rows":[[1,"alice"],[2,"bob \"the, builder\""]]}"#
+        );
+        // Empty results still say what their columns are.
+        let out = write(
+            &people(),
+            "SELECT id FROM person WHERE id < 0",
+            RowFormat::Columns,
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"{"columns":["id"],"rows":[]}"#
+        );
+    }
+
+    #[test]
+    fn csv_is_quoted_only_when_needed() {
+        let out = write(&people(), "SELECT * FROM person", RowFormat::Csv);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "id,name,score,photo\r\n1,alice,1.5,AQI=\r\n2,\"bob \"\"the, builder\"\"\",,\r\n"
+        );
+        // The output is always valid UTF-8, even if the text in the database isn't.
+        let out = write(
+            &people(),
+            "SELECT CAST(x'ff2c' AS TEXT) AS t",
+            RowFormat::Csv,
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), "t\r\n/yw=\r\n");
+    }
+
+    #[test]
+    fn cbor_has_the_columns_shape() {
+        let out = write(&people(), "SELECT id, photo FROM person", RowFormat::Cbor);
+        let value: Value = ciborium::de::from_reader(out.as_slice()).unwrap();
+        assert_eq!(
+            value,
+            Value::Map(vec![
+                (
+                    Value::Text("columns".to_owned()),
+                    Value::Array(vec![
+                        Value::Text("id".to_owned()),
+                        Value::Text("photo".to_owned())
+                    ])
+                ),
+                (
+                    Value::Text("rows".to_owned()),
+                    Value::Array(vec![
+                        Value::Array(vec![Value::Integer(1.into()), Value::Bytes(vec![1, 2])]),
+                        Value::Array(vec![Value::Integer(2.into()), Value::Null]),
+                    ])
+                ),
+            ])
+        );
+    }
+}
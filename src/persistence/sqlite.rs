@@ -1,15 +1,38 @@
-use crate::core::{MutationPolicy, Policy, QueryPolicy};
-use crate::persistence::{Persistence, PersistenceError, PersistenceResult};
+use crate::analyzer::{
+    describe_policy, expand_array_params, has_order_by, is_auth_param, rewrite_auth_params,
+    template_param_name, validate_policy, Schema,
+};
+use crate::core::{
+    BatchStep, Migration, MutationPolicy, PageRequest, Policy, PolicyChange, QueryPolicy,
+};
+use crate::persistence::format::{row_value, write_rows};
+use crate::persistence::{
+    AppliedMigration, MigrationReport, MutationCounts, MutationResult, Persistence,
+    PersistenceError, PersistenceResult, PolicyRevision, QueryPage, RowFormat, VersionedPolicy,
+};
+use crate::rules::{Caller, Rule};
 use crate::tokens::DatabaseAddress;
+use crate::values::{self, Tagged, ValueEncoding};
 use log::debug;
+use rand::RngCore;
+use ring::hmac;
+use rusqlite::config::DbConfig;
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::limits::Limit;
 use rusqlite::types::{FromSql, FromSqlError, ToSql, ToSqlOutput, ValueRef};
-use rusqlite::{Connection, Transaction, NO_PARAMS};
-use serde::ser::Serializer;
-use serde::Serialize;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Params, Statement, Transaction};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::BTreeMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+#[derive(Clone)]
 pub enum SqliteFactory {
     InMemory,
     FileSystem { dir: PathBuf },
@@ -33,23 +56,604 @@ impl SqliteFactory {
             }
         }
     }
+
+    /// Opens `count` read-only connections to a database that has already been opened
+    /// with `open`. In-memory databases can't be shared between connections, so they
+    /// get none.
+    pub fn open_readers(
+        &self,
+        db_addr: &DatabaseAddress,
+        count: usize,
+    ) -> PersistenceResult<Vec<SqlitePersistence>> {
+        match self {
+            SqliteFactory::InMemory => Ok(Vec::new()),
+            SqliteFactory::FileSystem { dir } => {
+                let mut path = dir.clone();
+                path.push(db_addr.filename());
+                (0..count)
+                    .map(|_| SqlitePersistence::from_file_read_only(&path))
+                    .collect()
+            }
+        }
+    }
+
+    /// Opens a bare connection to one of ezdb's own databases, which live alongside the
+    /// tenant databases but can never collide with a `DatabaseAddress::filename`.
+    pub fn connect(&self, filename: &str) -> PersistenceResult<Connection> {
+        match self {
+            SqliteFactory::InMemory => Ok(Connection::open_in_memory()?),
+            SqliteFactory::FileSystem { dir } => {
+                let mut path = dir.clone();
+                path.push(filename);
+                Ok(Connection::open(path)?)
+            }
+        }
+    }
 }
 
+/// Caps on what a single statement can do, set with `sqlite3_limit`. SQLite's defaults
+/// are sized for callers it can trust, so these are well below them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SqliteLimits {
+    /// The longest a string or blob may be, in bytes.
+    pub length: i32,
+    /// The longest a statement's SQL may be, in bytes.
+    pub sql_length: i32,
+    /// How deeply expressions may nest.
+    pub expr_depth: i32,
+    /// The most `SELECT`s a compound `SELECT` may combine.
+    pub compound_select: i32,
+    /// The most params a statement may bind, counting the ones arrays expand into.
+    pub variable_number: i32,
+    /// How deeply triggers may set each other off.
+    pub trigger_depth: i32,
+    /// The longest a `LIKE` or `GLOB` pattern may be, in bytes.
+    pub like_pattern_length: i32,
+}
+
+impl Default for SqliteLimits {
+    fn default() -> SqliteLimits {
+        SqliteLimits {
+            length: 16 << 20,
+            sql_length: 1 << 20,
+            expr_depth: 100,
+            compound_select: 50,
+            variable_number: 10_000,
+            trigger_depth: 32,
+            like_pattern_length: 10_000,
+        }
+    }
+}
+
+impl SqliteLimits {
+    fn values(&self) -> [(Limit, i32); 8] {
+        [
+            (Limit::SQLITE_LIMIT_LENGTH, self.length),
+            (Limit::SQLITE_LIMIT_SQL_LENGTH, self.sql_length),
+            (Limit::SQLITE_LIMIT_EXPR_DEPTH, self.expr_depth),
+            (Limit::SQLITE_LIMIT_COMPOUND_SELECT, self.compound_select),
+            (Limit::SQLITE_LIMIT_VARIABLE_NUMBER, self.variable_number),
+            (Limit::SQLITE_LIMIT_TRIGGER_DEPTH, self.trigger_depth),
+            (
+                Limit::SQLITE_LIMIT_LIKE_PATTERN_LENGTH,
+                self.like_pattern_length,
+            ),
+            // Room for plain `VACUUM`'s temporary database, and nothing else.
+            (Limit::SQLITE_LIMIT_ATTACHED, 1),
+        ]
+    }
+}
+
+// The number of virtual machine instructions between checks of a statement's deadline.
+const PROGRESS_HANDLER_PERIOD: i32 = 1000;
+
 pub struct SqlitePersistence {
     conn: Connection,
+    default_timeout: Option<Duration>,
+    max_rows: usize,
+    // The `SqlSource` of the SQL the connection is running.
+    source: Arc<AtomicU8>,
+    // Set when the job the connection is running is cancelled, and cleared when the
+    // next one starts.
+    cancelled: Arc<AtomicBool>,
 }
 impl SqlitePersistence {
+    fn new(conn: Connection) -> SqlitePersistence {
+        let persistence = SqlitePersistence {
+            conn,
+            default_timeout: None,
+            max_rows: usize::MAX,
+            source: Arc::new(AtomicU8::new(SqlSource::Ezdb as u8)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+        persistence.install_authorizer(false);
+        persistence
+    }
     pub fn in_memory() -> PersistenceResult<SqlitePersistence> {
         let conn = Connection::open_in_memory().unwrap();
         initialize_metadata(&conn)?;
-        Ok(SqlitePersistence { conn })
+        Ok(SqlitePersistence::new(conn))
     }
     pub fn from_file(path: &Path) -> PersistenceResult<SqlitePersistence> {
         let conn = Connection::open(path)?;
+        // In WAL mode, readers don't block the writer and the writer doesn't block them.
+        conn.pragma_update(None, "journal_mode", "WAL")?;
         initialize_metadata(&conn)?;
-        Ok(SqlitePersistence { conn })
+        Ok(SqlitePersistence::new(conn))
+    }
+    /// Opens a connection that can't modify the database. The database must already
+    /// have been initialized by `from_file`.
+    pub fn from_file_read_only(path: &Path) -> PersistenceResult<SqlitePersistence> {
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX
+            | OpenFlags::SQLITE_OPEN_URI;
+        let conn = Connection::open_with_flags(path, flags)?;
+        Ok(SqlitePersistence::new(conn))
+    }
+
+    /// Aborts statements that run for longer than `timeout`, unless their template
+    /// says otherwise.
+    pub fn with_default_timeout(mut self, timeout: Option<Duration>) -> SqlitePersistence {
+        self.default_timeout = timeout;
+        self
+    }
+
+    /// Limits queries to returning `max_rows` rows. Bigger results have to be paged
+    /// through with `query_page`.
+    pub fn with_max_rows(mut self, max_rows: usize) -> SqlitePersistence {
+        self.max_rows = max_rows;
+        self
+    }
+
+    /// Keeps admin SQL from reaching outside the database: attaching database files,
+    /// `VACUUM INTO` and loading extensions are refused, and every statement is held to
+    /// `limits`. Templates can't do any of that to begin with.
+    pub fn harden(self, limits: &SqliteLimits) -> PersistenceResult<SqlitePersistence> {
+        self.install_authorizer(true);
+        self.conn
+            .set_db_config(DbConfig::SQLITE_DBCONFIG_DEFENSIVE, true)?;
+        for (limit, value) in limits.values() {
+            self.conn.set_limit(limit, value);
+        }
+        Ok(self)
+    }
+
+    fn install_authorizer(&self, hardened: bool) {
+        let current = self.source.clone();
+        self.conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+            let source = SqlSource::from(current.load(Ordering::Relaxed));
+            authorize(source, hardened, ctx)
+        }));
+    }
+
+    // Runs `f` with the authorizer treating every statement it prepares or runs as
+    // coming from `source`.
+    fn as_source<T>(
+        &self,
+        source: SqlSource,
+        f: impl FnOnce() -> PersistenceResult<T>,
+    ) -> PersistenceResult<T> {
+        let previous = self.source.swap(source as u8, Ordering::Relaxed);
+        let result = f();
+        self.source.store(previous, Ordering::Relaxed);
+        result
+    }
+
+    // Prepares an admin's statement, and runs it with `f` before the default timeout.
+    // SQLite checks a statement when it's prepared, so whatever it checks while `f` runs
+    // is SQL of its own, like the copy `VACUUM` makes.
+    fn run_admin<T>(
+        &self,
+        sql: &str,
+        f: impl FnOnce(&mut Statement<'_>) -> PersistenceResult<T>,
+    ) -> PersistenceResult<T> {
+        let mut stmt = self.as_source(SqlSource::Admin, || Ok(self.conn.prepare(sql)?))?;
+        self.as_source(SqlSource::AdminRunning, || {
+            self.with_deadline(self.default_timeout, || f(&mut stmt))
+        })
+    }
+
+    // Runs `f`, aborting whatever statement it's running once `timeout` has passed.
+    fn with_deadline<T>(
+        &self,
+        timeout: Option<Duration>,
+        f: impl FnOnce() -> PersistenceResult<T>,
+    ) -> PersistenceResult<T> {
+        self.check_cancelled()?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let flag = exceeded.clone();
+        let cancelled = self.cancelled.clone();
+        self.conn.progress_handler(
+            PROGRESS_HANDLER_PERIOD,
+            Some(move || {
+                let expired = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+                if expired {
+                    flag.store(true, Ordering::Relaxed);
+                }
+                expired || cancelled.load(Ordering::Relaxed)
+            }),
+        );
+        let result = f();
+        self.conn.progress_handler(0, None::<fn() -> bool>);
+        match result {
+            Err(PersistenceError::Interrupted) if exceeded.load(Ordering::Relaxed) => {
+                Err(PersistenceError::DeadlineExceeded)
+            }
+            result => result,
+        }
+    }
+
+    // Fails with `Interrupted` if the job the connection is running has been cancelled.
+    fn check_cancelled(&self) -> PersistenceResult<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(PersistenceError::Interrupted);
+        }
+        Ok(())
+    }
+
+    // Runs a named query, failing if it returns more than `max_rows` rows. The caller
+    // is responsible for the transaction.
+    fn run_query(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<Value> {
+        let (rows, more) = self.run_query_page(caller, name, params, 0, self.max_rows, encoding)?;
+        if more {
+            return Err(PersistenceError::TooManyRows(self.max_rows));
+        }
+        Ok(rows)
+    }
+
+    // Runs a named query, returning up to `limit` of its rows after the first `offset`,
+    // and whether there are more after those.
+    fn run_query_page(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        offset: usize,
+        limit: usize,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<(Value, bool)> {
+        self.run_template("query", caller, name, params, |stmt, params| {
+            collect_rows(stmt, params, offset, limit, encoding)
+        })
+    }
+
+    // Runs a named query, writing up to `limit` of its rows after the first `offset` to
+    // `out`, and returning whether there are more after those. The caller is responsible
+    // for the transaction.
+    #[allow(clippy::too_many_arguments)]
+    fn run_stream(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        (offset, limit): (usize, usize),
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<bool> {
+        self.run_template("query", caller, name, params, |stmt, params| {
+            write_rows(stmt, params, format, encoding, offset, limit, out)
+        })
+    }
+
+    // Runs a named mutation. The caller is responsible for the transaction.
+    fn run_mutation(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<MutationResult> {
+        self.run_template("mutation", caller, name, params, |stmt, params| {
+            self.execute(|| self.collect_returned(stmt, params, encoding))
+        })
+        .map(|(rows, counts)| MutationResult { counts, rows })
+    }
+
+    // Runs a named mutation, writing the rows it returns to `out`. The caller is
+    // responsible for the transaction.
+    fn run_mutation_stream(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<MutationCounts> {
+        self.run_template("mutation", caller, name, params, |stmt, params| {
+            self.execute(|| self.write_returned(stmt, params, format, encoding, out))
+        })
+        .map(|((), counts)| counts)
+    }
+
+    // Where the page of a named query's results that `page` asks for starts, and the most
+    // rows it can have. Pages only line up if the query orders its rows.
+    fn page_bounds(
+        &self,
+        name: &str,
+        params: &BTreeMap<String, Value>,
+        page: PageRequest,
+    ) -> PersistenceResult<(usize, usize)> {
+        let raw_sql: String = self
+            .conn
+            .query_row(
+                "SELECT raw_sql FROM __ezdb_metadata__ WHERE type = 'query' AND name = ?",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| PersistenceError::NoSuchQuery(name.to_owned()))?;
+        if !has_order_by(&raw_sql) {
+            return Err(PersistenceError::InvalidArgument(format!(
+                "query {} has no ORDER BY, so it can't be paged",
+                name
+            )));
+        }
+        let limit = match page.limit {
+            Some(0) => {
+                return Err(PersistenceError::InvalidArgument(
+                    "limit must be positive".to_owned(),
+                ))
+            }
+            Some(limit) => limit.min(self.max_rows),
+            None => self.max_rows,
+        };
+        let offset = match page.page_token {
+            Some(token) => PageToken::decode(&token, &self.page_token_key()?, name, params)?.offset,
+            None => 0,
+        };
+        Ok((offset, limit))
+    }
+
+    // Prepares a template, if its rule lets `caller` run it with `params`, and runs it
+    // with `f` before its timeout.
+    fn run_template<T>(
+        &self,
+        kind: &str,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        f: impl FnOnce(&mut Statement<'_>, &[(&str, &dyn ToSql)]) -> PersistenceResult<T>,
+    ) -> PersistenceResult<T> {
+        let (raw_sql, timeout) = self.template(kind, name, caller, &params)?;
+        self.as_source(SqlSource::Template, || {
+            let (mut stmt, params) = self.prepare_named(&raw_sql, params)?;
+            let params: Vec<(&str, &dyn ToSql)> = params
+                .iter()
+                .map(|(k, v)| (k.as_ref(), v as &dyn ToSql))
+                .collect();
+            self.with_deadline(timeout, || f(&mut stmt, params.as_slice()))
+        })
+    }
+
+    // Runs a raw statement that may modify the database, with `f` reading the rows it
+    // returns. A statement that returns rows runs in a transaction of its own, so that
+    // it can be undone if it returns too many. Other statements may not be able to run
+    // in one, like `VACUUM`.
+    fn run_raw_mutation<T>(
+        &self,
+        sql: &str,
+        f: impl FnOnce(&mut Statement<'_>) -> PersistenceResult<T>,
+    ) -> PersistenceResult<(T, MutationCounts)> {
+        self.run_admin(sql, |stmt| {
+            let txn = if stmt.column_count() > 0 && self.conn.is_autocommit() {
+                Some(self.conn.unchecked_transaction()?)
+            } else {
+                None
+            };
+            let result = self.execute(|| f(stmt))?;
+            if let Some(txn) = txn {
+                txn.commit()?;
+            }
+            Ok(result)
+        })
+    }
+
+    // Runs `f`, which runs a statement that may modify the database along with any
+    // `RETURNING` clause, and counts what the statement changed.
+    fn execute<T>(
+        &self,
+        f: impl FnOnce() -> PersistenceResult<T>,
+    ) -> PersistenceResult<(T, MutationCounts)> {
+        let before = self.total_changes()?;
+        let result = f()?;
+        // `changes()` still counts the last INSERT, UPDATE or DELETE after statements
+        // that don't modify any rows, like `CREATE TABLE`.
+        let changes = if self.total_changes()? == before {
+            0
+        } else {
+            self.conn.changes()
+        };
+        let counts = MutationCounts {
+            changes,
+            last_insert_rowid: self.conn.last_insert_rowid(),
+        };
+        Ok((result, counts))
+    }
+
+    // Collects the rows a mutation returns. There may be no more of them than a query
+    // could return, since they're held in memory just the same.
+    fn collect_returned<P: Params>(
+        &self,
+        stmt: &mut Statement,
+        params: P,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<Value> {
+        let (rows, more) = collect_rows(stmt, params, 0, self.max_rows, encoding)?;
+        if more {
+            return Err(PersistenceError::TooManyRows(self.max_rows));
+        }
+        Ok(rows)
+    }
+
+    // Like `collect_returned`, but writes the rows to `out` instead.
+    fn write_returned<P: Params>(
+        &self,
+        stmt: &mut Statement,
+        params: P,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<()> {
+        if write_rows(stmt, params, format, encoding, 0, self.max_rows, out)? {
+            return Err(PersistenceError::TooManyRows(self.max_rows));
+        }
+        Ok(())
+    }
+
+    // Page tokens are signed with a key of the database's own, so clients can't forge
+    // them to start a page anywhere.
+    fn page_token_key(&self) -> PersistenceResult<hmac::Key> {
+        let key: Vec<u8> = self.conn.query_row(
+            "SELECT value FROM __ezdb_secrets__ WHERE name = 'pageTokens'",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(hmac::Key::new(hmac::HMAC_SHA256, &key))
+    }
+
+    fn total_changes(&self) -> PersistenceResult<i64> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT total_changes()")?
+            .query_row([], |row| row.get(0))?)
+    }
+
+    // Looks up a template's SQL and how long it may run for, if its rule lets `caller`
+    // run it with `params`.
+    fn template(
+        &self,
+        kind: &str,
+        name: String,
+        caller: &Caller,
+        params: &BTreeMap<String, Value>,
+    ) -> PersistenceResult<(String, Option<Duration>)> {
+        let (raw_sql, rule, timeout_ms): (String, Rule, Option<u64>) = self
+            .conn
+            .query_row(
+                "SELECT raw_sql, rule, timeout_ms FROM __ezdb_metadata__ WHERE type = ? AND name = ?",
+                [kind, &name],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .map_err(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => PersistenceError::NoSuchQuery(name),
+                e => e.into(),
+            })?;
+        if !rule.allows(caller, params) {
+            return Err(PersistenceError::PermissionDenied(format!(
+                "rule `{}` does not allow this request",
+                rule
+            )));
+        }
+        let timeout = timeout_ms
+            .map(Duration::from_millis)
+            .or(self.default_timeout);
+        Ok((raw_sql, timeout))
     }
+
+    // Prepares a named template to be bound with `params`. `IN` lists are expanded for
+    // the params that are arrays, so the SQL may differ from request to request.
+    fn prepare_named(
+        &self,
+        raw_sql: &str,
+        params: BTreeMap<String, Value>,
+    ) -> PersistenceResult<(Statement<'_>, Vec<(String, MyValue)>)> {
+        let arrays: HashSet<&str> = params
+            .iter()
+            .filter(|(_, v)| v.is_array())
+            .map(|(k, _)| k.as_str())
+            .collect();
+        let sql = expand_array_params(&rewrite_auth_params(raw_sql), &arrays);
+        let stmt = self.conn.prepare(&sql)?;
+        let params = named_params(&stmt, params)?;
+        Ok((stmt, params))
+    }
+}
+// Collects up to `limit` rows after skipping the first `offset`, without reading any
+// more than that. Also reports whether there were more rows after those.
+fn collect_rows<P: Params>(
+    stmt: &mut Statement,
+    params: P,
+    offset: usize,
+    limit: usize,
+    encoding: ValueEncoding,
+) -> PersistenceResult<(Value, bool)> {
+    let mut rows = stmt.query(params)?;
+    for _ in 0..offset {
+        if rows.next()?.is_none() {
+            return Ok((Value::Array(Vec::new()), false));
+        }
+    }
+    let mut collected = Vec::new();
+    while let Some(row) = rows.next()? {
+        if collected.len() == limit {
+            return Ok((Value::Array(collected), true));
+        }
+        collected.push(row_value(row, encoding)?);
+    }
+    Ok((Value::Array(collected), false))
 }
+
+// Where the next page of a named query's results starts. Clients treat it as opaque,
+// and it's signed so they can't change it. It's an offset, so each page runs the query
+// again, and pages only line up if the template has an `ORDER BY` that gives every row
+// a fixed place.
+#[derive(Serialize, Deserialize)]
+struct PageToken {
+    query: String,
+    // The checksum of the params, so a token can't carry an offset over to a query
+    // with different ones.
+    params: String,
+    offset: usize,
+}
+
+impl PageToken {
+    fn new(query: String, params: &BTreeMap<String, Value>, offset: usize) -> PageToken {
+        PageToken {
+            query,
+            params: params_checksum(params),
+            offset,
+        }
+    }
+
+    // The token's JSON, then its signature.
+    fn encode(&self, key: &hmac::Key) -> String {
+        let json = serde_json::to_vec(self).expect("serialize");
+        let tag = hmac::sign(key, &json);
+        format!(
+            "{}.{}",
+            base64::encode_config(&json, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(tag, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    // Only accepts tokens that were signed with `key` and issued for the query named
+    // `query`, with `params`.
+    fn decode(
+        token: &str,
+        key: &hmac::Key,
+        query: &str,
+        params: &BTreeMap<String, Value>,
+    ) -> PersistenceResult<PageToken> {
+        let decode = |part| base64::decode_config(part, base64::URL_SAFE_NO_PAD).ok();
+        token
+            .split_once('.')
+            .and_then(|(json, tag)| Some((decode(json)?, decode(tag)?)))
+            .filter(|(json, tag)| hmac::verify(key, json, tag).is_ok())
+            .and_then(|(json, _)| serde_json::from_slice::<PageToken>(&json).ok())
+            .filter(|token| token.query == query && token.params == params_checksum(params))
+            .ok_or_else(|| PersistenceError::InvalidArgument("invalid page token".to_owned()))
+    }
+}
+
 fn initialize_metadata(conn: &Connection) -> PersistenceResult<()> {
     conn.execute(
         r#"
@@ -57,137 +661,757 @@ fn initialize_metadata(conn: &Connection) -> PersistenceResult<()> {
             type TEXT NOT NULL,
             name TEXT NOT NULL,
             raw_sql TEXT NOT NULL,
+            rule TEXT NOT NULL DEFAULT 'anyone',
+            timeout_ms INTEGER,
             PRIMARY KEY (type, name)
         )
     "#,
-        NO_PARAMS,
+        [],
+    )?;
+    // Databases created by older versions of ezdb may be missing newer columns.
+    ensure_column(
+        conn,
+        "__ezdb_metadata__",
+        "rule",
+        "TEXT NOT NULL DEFAULT 'anyone'",
+    )?;
+    ensure_column(conn, "__ezdb_metadata__", "timeout_ms", "INTEGER")?;
+    // Every policy that's been accepted, as JSON, numbered by the version it created.
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS __ezdb_policy_revisions__ (
+            revision INTEGER PRIMARY KEY,
+            policy TEXT NOT NULL,
+            created_at INTEGER NOT NULL,
+            author TEXT
+        )
+    "#,
+        [],
+    )?;
+    // The migrations that have been applied, in the order they were.
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS __ezdb_migrations__ (
+            position INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            checksum TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        )
+    "#,
+        [],
+    )?;
+    // Keys that ezdb signs things with, like page tokens. Inserting into a table without
+    // rowids leaves the connection's last insert rowid alone.
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS __ezdb_secrets__ (
+            name TEXT PRIMARY KEY,
+            value BLOB NOT NULL
+        ) WITHOUT ROWID
+    "#,
+        [],
+    )?;
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    conn.execute(
+        "INSERT OR IGNORE INTO __ezdb_secrets__ (name, value) VALUES ('pageTokens', ?)",
+        [&key[..]],
     )?;
     Ok(())
 }
 
+// Reads the policy. The caller is responsible for the transaction.
+fn read_policy(conn: &Connection) -> PersistenceResult<VersionedPolicy> {
+    let version = policy_version(conn)?;
+    let mut queries = conn.prepare(
+        "SELECT name, raw_sql, rule, timeout_ms FROM __ezdb_metadata__ WHERE type = 'query'",
+    )?;
+    let queries: Vec<QueryPolicy> = queries
+        .query_map([], |row| {
+            let name: String = row.get(0)?;
+            let raw_sql: String = row.get(1)?;
+            let rule: Rule = row.get(2)?;
+            let timeout_ms: Option<u64> = row.get(3)?;
+            Ok(QueryPolicy {
+                name,
+                raw_sql,
+                rule,
+                timeout_ms,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    let mut mutations = conn.prepare(
+        "SELECT name, raw_sql, rule, timeout_ms FROM __ezdb_metadata__ WHERE type = 'mutation'",
+    )?;
+    let mutations: Vec<MutationPolicy> = mutations
+        .query_map([], |row| {
+            let name: String = row.get(0)?;
+            let raw_sql: String = row.get(1)?;
+            let rule: Rule = row.get(2)?;
+            let timeout_ms: Option<u64> = row.get(3)?;
+            Ok(MutationPolicy {
+                name,
+                raw_sql,
+                rule,
+                timeout_ms,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(VersionedPolicy {
+        version,
+        policy: Policy { queries, mutations },
+    })
+}
+
+fn read_revision(conn: &Connection, revision: u64) -> PersistenceResult<PolicyRevision> {
+    let (created_at, author, policy): (i64, Option<String>, String) = conn
+        .query_row(
+            "SELECT created_at, author, policy FROM __ezdb_policy_revisions__ WHERE revision = ?",
+            [revision as i64],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?
+        .ok_or(PersistenceError::NoSuchRevision(revision))?;
+    let policy =
+        serde_json::from_str(&policy).map_err(|e| PersistenceError::Unknown(e.to_string()))?;
+    Ok(PolicyRevision {
+        revision,
+        created_at,
+        author,
+        policy: Some(policy),
+    })
+}
+
+/// Whose SQL a connection is running. ezdb's own statements may do anything, admins'
+/// may do anything but touch ezdb's tables, and templates may only read and write rows.
+/// Migrations are admins' too, but they can't end or roll back the transaction they're
+/// applied in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum SqlSource {
+    Ezdb,
+    Admin,
+    /// The SQL SQLite runs on behalf of an admin's statement, once it's been prepared.
+    AdminRunning,
+    Migration,
+    Template,
+}
+
+impl From<u8> for SqlSource {
+    fn from(source: u8) -> SqlSource {
+        match source {
+            s if s == SqlSource::Ezdb as u8 => SqlSource::Ezdb,
+            s if s == SqlSource::Admin as u8 => SqlSource::Admin,
+            s if s == SqlSource::AdminRunning as u8 => SqlSource::AdminRunning,
+            s if s == SqlSource::Migration as u8 => SqlSource::Migration,
+            _ => SqlSource::Template,
+        }
+    }
+}
+
+// Pragmas that templates may run without an argument, since then they only read a
+// setting. Others, like `optimize` or `wal_checkpoint`, do something even without one,
+// or give away more than templates need, like `database_list` does the file's path.
+const TEMPLATE_READ_PRAGMAS: &[&str] = &[
+    "application_id",
+    "data_version",
+    "encoding",
+    "foreign_keys",
+    "schema_version",
+    "user_version",
+];
+
+// Pragmas that templates may pass an argument to, since they only describe the schema.
+const TEMPLATE_PRAGMAS: &[&str] = &[
+    "table_info",
+    "table_xinfo",
+    "index_list",
+    "index_info",
+    "index_xinfo",
+    "foreign_key_list",
+];
+
+fn authorize(source: SqlSource, hardened: bool, ctx: AuthContext<'_>) -> Authorization {
+    if source == SqlSource::Ezdb {
+        return Authorization::Allow;
+    }
+    // Views and triggers can't be used to get at ezdb's tables either, since they're
+    // checked as part of the statements that use them. Nor can another name for the
+    // same file, so the tables are protected in every database but one: the copy
+    // `VACUUM` makes as it runs, which it always calls `vacuum_db`. An admin can attach
+    // a database of that name too, but their statements are checked before they run.
+    let vacuuming = source == SqlSource::AdminRunning && ctx.database_name == Some("vacuum_db");
+    if !vacuuming && touched_tables(&ctx.action).any(is_internal_table) {
+        return Authorization::Deny;
+    }
+    let source = match source {
+        SqlSource::AdminRunning => SqlSource::Admin,
+        source => source,
+    };
+    let allowed = match (source, ctx.action) {
+        (SqlSource::Migration, AuthAction::Transaction { .. })
+        | (SqlSource::Migration, AuthAction::Savepoint { .. }) => false,
+        // Plain `VACUUM` attaches an empty temporary database of its own, which is all
+        // an empty filename can open.
+        (SqlSource::Admin | SqlSource::Migration, AuthAction::Attach { filename }) if hardened => {
+            filename.is_empty()
+        }
+        (SqlSource::Admin | SqlSource::Migration, AuthAction::Function { function_name })
+            if hardened =>
+        {
+            !function_name.eq_ignore_ascii_case("load_extension")
+        }
+        // Actions SQLite doesn't describe fully, like `ATTACH` of a filename that isn't a
+        // literal.
+        (SqlSource::Admin | SqlSource::Migration, AuthAction::Unknown { .. }) => !hardened,
+        (SqlSource::Admin | SqlSource::Migration, _) => true,
+        (_, AuthAction::Select)
+        | (_, AuthAction::Read { .. })
+        | (_, AuthAction::Insert { .. })
+        | (_, AuthAction::Update { .. })
+        | (_, AuthAction::Delete { .. })
+        | (_, AuthAction::Recursive) => true,
+        (_, AuthAction::Function { function_name }) => {
+            !function_name.eq_ignore_ascii_case("load_extension")
+        }
+        (
+            _,
+            AuthAction::Pragma {
+                pragma_name,
+                pragma_value,
+            },
+        ) => {
+            let listed =
+                |pragmas: &[&str]| pragmas.iter().any(|p| p.eq_ignore_ascii_case(pragma_name));
+            listed(TEMPLATE_PRAGMAS) || (pragma_value.is_none() && listed(TEMPLATE_READ_PRAGMAS))
+        }
+        _ => false,
+    };
+    if allowed {
+        Authorization::Allow
+    } else {
+        Authorization::Deny
+    }
+}
+
+fn touched_tables<'a>(action: &AuthAction<'a>) -> impl Iterator<Item = &'a str> {
+    let tables: [Option<&'a str>; 2] = match *action {
+        AuthAction::CreateIndex {
+            index_name,
+            table_name,
+        }
+        | AuthAction::CreateTempIndex {
+            index_name,
+            table_name,
+        }
+        | AuthAction::DropIndex {
+            index_name,
+            table_name,
+        }
+        | AuthAction::DropTempIndex {
+            index_name,
+            table_name,
+        } => [Some(index_name), Some(table_name)],
+        AuthAction::CreateTrigger {
+            trigger_name,
+            table_name,
+        }
+        | AuthAction::CreateTempTrigger {
+            trigger_name,
+            table_name,
+        }
+        | AuthAction::DropTrigger {
+            trigger_name,
+            table_name,
+        }
+        | AuthAction::DropTempTrigger {
+            trigger_name,
+            table_name,
+        } => [Some(trigger_name), Some(table_name)],
+        AuthAction::CreateTable { table_name }
+        | AuthAction::CreateTempTable { table_name }
+        | AuthAction::DropTable { table_name }
+        | AuthAction::DropTempTable { table_name }
+        | AuthAction::Insert { table_name }
+        | AuthAction::Delete { table_name }
+        | AuthAction::Read { table_name, .. }
+        | AuthAction::Update { table_name, .. }
+        | AuthAction::AlterTable { table_name, .. }
+        | AuthAction::Analyze { table_name }
+        | AuthAction::CreateVtable { table_name, .. }
+        | AuthAction::DropVtable { table_name, .. } => [Some(table_name), None],
+        AuthAction::CreateView { view_name }
+        | AuthAction::CreateTempView { view_name }
+        | AuthAction::DropView { view_name }
+        | AuthAction::DropTempView { view_name } => [Some(view_name), None],
+        AuthAction::Reindex { index_name } => [Some(index_name), None],
+        _ => [None, None],
+    };
+    IntoIterator::into_iter(tables).flatten()
+}
+
+fn is_internal_table(name: &str) -> bool {
+    name.get(..INTERNAL_TABLE_PREFIX.len())
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(INTERNAL_TABLE_PREFIX))
+}
+
+// ezdb's own tables, like `__ezdb_metadata__`, all start with this.
+const INTERNAL_TABLE_PREFIX: &str = "__ezdb_";
+
+fn read_migrations(conn: &Connection) -> PersistenceResult<Vec<AppliedMigration>> {
+    let mut stmt = conn
+        .prepare("SELECT name, checksum, applied_at FROM __ezdb_migrations__ ORDER BY position")?;
+    let migrations = stmt
+        .query_map([], |row| {
+            Ok(AppliedMigration {
+                name: row.get(0)?,
+                checksum: row.get(1)?,
+                applied_at: row.get(2)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(migrations)
+}
+
+fn checksum(sql: &str) -> String {
+    format!("{:x}", Sha256::digest(sql.as_bytes()))
+}
+
+// The params are kept in a `BTreeMap`, so the same ones always serialize the same way.
+fn params_checksum(params: &BTreeMap<String, Value>) -> String {
+    checksum(&serde_json::to_string(params).expect("serialize"))
+}
+
+// The policy's version has a row of its own in the metadata table, in its `raw_sql`
+// column. Policies from before versions were kept are version 0.
+fn policy_version(conn: &Connection) -> PersistenceResult<u64> {
+    let version: Option<i64> = conn
+        .query_row(
+            "SELECT CAST(raw_sql AS INTEGER) FROM __ezdb_metadata__ WHERE type = 'version' AND name = 'policy'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(version.unwrap_or(0) as u64)
+}
+
+fn set_policy_version(conn: &Connection, version: u64) -> PersistenceResult<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO __ezdb_metadata__ (type, name, raw_sql) VALUES ('version', 'policy', ?)",
+        [version.to_string()],
+    )?;
+    Ok(())
+}
+
+fn ensure_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> PersistenceResult<()> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(1) > 0 FROM pragma_table_info(?) WHERE name = ?",
+        [table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
 impl Persistence for SqlitePersistence {
     fn query_named(
         &self,
+        caller: &Caller,
         name: String,
         params: BTreeMap<String, Value>,
+        encoding: ValueEncoding,
     ) -> PersistenceResult<Value> {
         debug!("running named query: {}", name);
         let txn = self.conn.unchecked_transaction()?;
-        let query: String = txn
-            .query_row(
-                "SELECT raw_sql FROM __ezdb_metadata__ WHERE type = 'query' AND name = ?",
-                &[&name],
-                |row| row.get(0),
-            )
-            .map_err(|_| PersistenceError::NoSuchQuery(name))?;
-        let params: Vec<(String, MyValue)> =
-            params.into_iter().map(|(k, v)| (k, v.into())).collect();
-        let params: Vec<(&str, &dyn ToSql)> = params
-            .iter()
-            .map(|(k, v)| (k.as_ref(), v as &dyn ToSql))
-            .collect();
-        let mut stmt = self.conn.prepare(&query)?;
-        let rows: Vec<BTreeMap<String, MyValue>> = stmt
-            .query_map_named(params.as_slice(), |row| {
-                let values: BTreeMap<String, MyValue> = (0..row.column_count())
-                    .map(|i| (row.column_name(i).unwrap().to_owned(), row.get_unwrap(i)))
-                    .collect();
-                Ok(values)
-            })?
-            .collect::<Result<Vec<_>, _>>()?;
+        let rows = self.run_query(caller, name, params, encoding)?;
         txn.commit()?;
-        Ok(serde_json::to_value(&rows).unwrap())
+        Ok(rows)
     }
-    fn mutate_named(&self, name: String, params: BTreeMap<String, Value>) -> PersistenceResult<()> {
-        debug!("performing named mutation: {}", name);
+    fn query_page(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        page: PageRequest,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<QueryPage> {
+        debug!("running named query: {}", name);
+        let (offset, limit) = self.page_bounds(&name, &params, page)?;
         let txn = self.conn.unchecked_transaction()?;
-        let mutation: String = txn.query_row(
-            "SELECT raw_sql FROM __ezdb_metadata__ WHERE type = 'mutation' AND name = ?",
-            &[&name],
-            |row| row.get(0),
+        let (rows, more) = self.run_query_page(
+            caller,
+            name.clone(),
+            params.clone(),
+            offset,
+            limit,
+            encoding,
         )?;
-        let params: Vec<(String, MyValue)> =
-            params.into_iter().map(|(k, v)| (k, v.into())).collect();
-        let params: Vec<(&str, &dyn ToSql)> = params
-            .iter()
-            .map(|(k, v)| (k.as_ref(), v as &dyn ToSql))
-            .collect();
-        let mut stmt = self.conn.prepare(&mutation)?;
-        stmt.execute_named(params.as_slice())?;
         txn.commit()?;
-        Ok(())
+        let next_page_token = if more {
+            Some(PageToken::new(name, &params, offset + limit).encode(&self.page_token_key()?))
+        } else {
+            None
+        };
+        Ok(QueryPage {
+            rows,
+            next_page_token,
+        })
+    }
+    fn stream_named(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        page: Option<PageRequest>,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<Option<String>> {
+        debug!("streaming named query: {}", name);
+        let (offset, limit, next) = match page {
+            Some(page) => {
+                let (offset, limit) = self.page_bounds(&name, &params, page)?;
+                let next = PageToken::new(name.clone(), &params, offset + limit);
+                (offset, limit, Some(next))
+            }
+            None => (0, usize::MAX, None),
+        };
+        let txn = self.conn.unchecked_transaction()?;
+        let more = self.run_stream(caller, name, params, (offset, limit), format, encoding, out)?;
+        txn.commit()?;
+        match next.filter(|_| more) {
+            Some(next) => Ok(Some(next.encode(&self.page_token_key()?))),
+            None => Ok(None),
+        }
+    }
+    fn mutate_named(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<MutationResult> {
+        debug!("performing named mutation: {}", name);
+        let txn = self.conn.unchecked_transaction()?;
+        let result = self.run_mutation(caller, name, params, encoding)?;
+        txn.commit()?;
+        Ok(result)
+    }
+    fn stream_mutation_named(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<MutationCounts> {
+        debug!("performing named mutation: {}", name);
+        let txn = self.conn.unchecked_transaction()?;
+        let counts = self.run_mutation_stream(caller, name, params, format, encoding, out)?;
+        txn.commit()?;
+        Ok(counts)
+    }
+    fn batch(
+        &self,
+        caller: &Caller,
+        steps: Vec<BatchStep>,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<Vec<Value>> {
+        debug!("running a batch of {} steps", steps.len());
+        // Dropping the transaction on an early return rolls back every step.
+        let txn = self.conn.unchecked_transaction()?;
+        let mut results = Vec::with_capacity(steps.len());
+        for (i, step) in steps.into_iter().enumerate() {
+            self.check_cancelled().map_err(|e| e.in_step(i))?;
+            let result = match step {
+                BatchStep::Query { name, params } => self.run_query(caller, name, params, encoding),
+                BatchStep::Mutation { name, params } => self
+                    .run_mutation(caller, name, params, encoding)
+                    .map(|result| serde_json::to_value(&result).unwrap()),
+            };
+            results.push(result.map_err(|e| e.in_step(i))?);
+        }
+        txn.commit()?;
+        Ok(results)
     }
 
-    fn query_raw(&self, query: String) -> PersistenceResult<Value> {
+    fn query_raw(&self, query: String, encoding: ValueEncoding) -> PersistenceResult<Value> {
         debug!("running query {}", query);
-        let mut stmt = self.conn.prepare(&query)?;
-        let rows: Vec<BTreeMap<String, MyValue>> = stmt
-            .query_map(NO_PARAMS, |row| {
-                let values: BTreeMap<String, MyValue> = (0..row.column_count())
-                    .map(|i| (row.column_name(i).unwrap().to_owned(), row.get_unwrap(i)))
-                    .collect();
-                Ok(values)
-            })?
-            .collect::<Result<Vec<_>, _>>()?;
-        Ok(serde_json::to_value(&rows).unwrap())
+        self.run_admin(&query, |stmt| {
+            let (rows, more) = collect_rows(stmt, [], 0, self.max_rows, encoding)?;
+            if more {
+                return Err(PersistenceError::TooManyRows(self.max_rows));
+            }
+            Ok(rows)
+        })
+    }
+    fn stream_raw(
+        &self,
+        query: String,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<()> {
+        debug!("streaming query {}", query);
+        self.run_admin(&query, |stmt| {
+            write_rows(stmt, [], format, encoding, 0, usize::MAX, out).map(|_| ())
+        })
     }
-    fn mutate_raw(&self, stmt: String) -> PersistenceResult<()> {
+    fn mutate_raw(
+        &self,
+        stmt: String,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<MutationResult> {
         debug!("running mutation {}", stmt);
-        self.conn.execute(&stmt, NO_PARAMS)?;
-        Ok(())
+        self.run_raw_mutation(&stmt, |stmt| self.collect_returned(stmt, [], encoding))
+            .map(|(rows, counts)| MutationResult { counts, rows })
     }
-    fn fetch_policy(&self) -> PersistenceResult<Policy> {
+    fn stream_mutation_raw(
+        &self,
+        stmt: String,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<MutationCounts> {
+        debug!("running mutation {}", stmt);
+        self.run_raw_mutation(&stmt, |stmt| {
+            self.write_returned(stmt, [], format, encoding, out)
+        })
+        .map(|((), counts)| counts)
+    }
+    fn fetch_policy(&self) -> PersistenceResult<VersionedPolicy> {
         debug!("fetching policy");
-        let mut queries = self
-            .conn
-            .prepare("SELECT name, raw_sql FROM __ezdb_metadata__ WHERE type = 'query'")?;
-        let queries: Vec<QueryPolicy> = queries
-            .query_map(NO_PARAMS, |row| {
-                let name: String = row.get(0)?;
-                let raw_sql: String = row.get(1)?;
-                Ok(QueryPolicy { name, raw_sql })
-            })?
-            .collect::<Result<_, _>>()?;
-        let mut mutations = self
-            .conn
-            .prepare("SELECT name, raw_sql FROM __ezdb_metadata__ WHERE type = 'mutation'")?;
-        let mutations: Vec<MutationPolicy> = mutations
-            .query_map(NO_PARAMS, |row| {
-                let name: String = row.get(0)?;
-                let raw_sql: String = row.get(1)?;
-                Ok(MutationPolicy { name, raw_sql })
-            })?
-            .collect::<Result<_, _>>()?;
-        Ok(Policy { queries, mutations })
+        let txn = self.conn.unchecked_transaction()?;
+        let policy = read_policy(&txn)?;
+        txn.commit()?;
+        Ok(policy)
     }
-    fn set_policy(&self, policy: Policy) -> PersistenceResult<()> {
-        debug!("updating policy to: {:?}", policy);
+    fn update_policy(
+        &self,
+        change: PolicyChange,
+        if_version: Option<u64>,
+        author: Option<String>,
+    ) -> PersistenceResult<u64> {
+        debug!("changing policy: {:?}", change);
         let mut txn = self.conn.unchecked_transaction()?;
-        txn.execute("DELETE FROM __ezdb_metadata__", NO_PARAMS)?;
+        let current = read_policy(&txn)?;
+        if let Some(expected) = if_version.filter(|v| *v != current.version) {
+            return Err(PersistenceError::VersionMismatch {
+                expected,
+                actual: current.version,
+            });
+        }
+        // Even a change to one template is checked as part of the whole policy.
+        let policy = change.apply(current.policy, |revision| {
+            read_revision(&txn, revision)?
+                .policy
+                .ok_or(PersistenceError::NoSuchRevision(revision))
+        })?;
+        self.as_source(SqlSource::Template, || {
+            validate_policy(&txn, &policy).map_err(PersistenceError::InvalidPolicy)
+        })?;
+        txn.execute(
+            "DELETE FROM __ezdb_metadata__ WHERE type IN ('query', 'mutation')",
+            [],
+        )?;
+        let version = current.version + 1;
+        txn.execute(
+            "INSERT INTO __ezdb_policy_revisions__ (revision, policy, created_at, author) VALUES (?, ?, strftime('%s', 'now'), ?)",
+            params![
+                version as i64,
+                serde_json::to_string(&policy).expect("serialize"),
+                author
+            ],
+        )?;
         populate_policy(&mut txn, policy)?;
+        set_policy_version(&txn, version)?;
         txn.commit()?;
-        Ok(())
+        Ok(version)
+    }
+
+    fn policy_history(&self) -> PersistenceResult<Vec<PolicyRevision>> {
+        debug!("fetching policy history");
+        let mut stmt = self.conn.prepare(
+            "SELECT revision, created_at, author FROM __ezdb_policy_revisions__ ORDER BY revision",
+        )?;
+        let revisions = stmt
+            .query_map([], |row| {
+                Ok(PolicyRevision {
+                    revision: row.get::<_, i64>(0)? as u64,
+                    created_at: row.get(1)?,
+                    author: row.get(2)?,
+                    policy: None,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(revisions)
+    }
+    fn fetch_policy_revision(&self, revision: u64) -> PersistenceResult<PolicyRevision> {
+        debug!("fetching policy revision {}", revision);
+        read_revision(&self.conn, revision)
+    }
+
+    fn fetch_schema(&self) -> PersistenceResult<Schema> {
+        debug!("fetching schema");
+        let txn = self.conn.unchecked_transaction()?;
+        let policy = read_policy(&txn)?.policy;
+        let schema = self.as_source(SqlSource::Template, || {
+            describe_policy(&txn, &policy).map_err(PersistenceError::from)
+        })?;
+        txn.commit()?;
+        Ok(schema)
+    }
+
+    fn list_migrations(&self) -> PersistenceResult<Vec<AppliedMigration>> {
+        debug!("listing migrations");
+        read_migrations(&self.conn)
+    }
+    fn apply_migrations(
+        &self,
+        migrations: Vec<Migration>,
+        dry_run: bool,
+    ) -> PersistenceResult<MigrationReport> {
+        debug!(
+            "applying {} migrations{}",
+            migrations.len(),
+            if dry_run { " (dry run)" } else { "" }
+        );
+        let mut names = HashSet::new();
+        if let Some(m) = migrations.iter().find(|m| !names.insert(&m.name)) {
+            return Err(PersistenceError::InvalidArgument(format!(
+                "migration {} is listed more than once",
+                m.name
+            )));
+        }
+        // Dropping the transaction on an early return, or after a dry run, rolls back
+        // every migration.
+        let txn = self.conn.unchecked_transaction()?;
+        let applied = read_migrations(&txn)?;
+        for (i, done) in applied.iter().enumerate() {
+            let message = match migrations.get(i) {
+                Some(m) if m.name == done.name && checksum(&m.sql) == done.checksum => continue,
+                Some(m) if m.name == done.name => "has been edited since it was applied",
+                _ => "was applied, but isn't listed in the same place",
+            };
+            return Err(PersistenceError::MigrationConflict {
+                name: done.name.clone(),
+                message: message.to_owned(),
+            });
+        }
+        let pending = &migrations[applied.len()..];
+        self.with_deadline(self.default_timeout, || {
+            for (i, m) in pending.iter().enumerate() {
+                let position = applied.len() + i;
+                self.as_source(SqlSource::Migration, || Ok(txn.execute_batch(&m.sql)?))
+                    .map_err(|e| e.in_step(position))?;
+                txn.execute(
+                    "INSERT INTO __ezdb_migrations__ (position, name, checksum, applied_at) VALUES (?, ?, ?, strftime('%s', 'now'))",
+                    params![position as i64, m.name, checksum(&m.sql)],
+                )?;
+            }
+            Ok(())
+        })?;
+        // A migration mustn't break the templates that are already live.
+        let policy = read_policy(&txn)?.policy;
+        self.as_source(SqlSource::Template, || {
+            validate_policy(&txn, &policy).map_err(PersistenceError::InvalidPolicy)
+        })?;
+        if !dry_run {
+            txn.commit()?;
+        }
+        Ok(MigrationReport {
+            applied: pending.iter().map(|m| m.name.clone()).collect(),
+            dry_run,
+        })
     }
 
     fn get_interrupt_handle(&self) -> rusqlite::InterruptHandle {
         self.conn.get_interrupt_handle()
     }
+
+    fn get_cancel_flag(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+}
+
+// Checks the caller's params against the ones the template actually binds. Every claim
+// of an authenticated caller is passed along as an `:auth.*` param, so unused ones are
+// ignored, and a claim that the template uses but the caller doesn't have binds NULL.
+fn named_params(
+    stmt: &Statement,
+    mut params: BTreeMap<String, Value>,
+) -> PersistenceResult<Vec<(String, MyValue)>> {
+    let mut named = Vec::with_capacity(stmt.parameter_count());
+    let mut missing = Vec::new();
+    // SQLite numbers params from 1. Anonymous params like `?` can't be bound by name.
+    for i in 1..=stmt.parameter_count() {
+        let sqlite_name = match stmt.parameter_name(i) {
+            Some(name) => name,
+            None => {
+                missing.push(format!("?{}", i));
+                continue;
+            }
+        };
+        let name = template_param_name(sqlite_name);
+        match params.remove(name.as_ref()) {
+            Some(v) => match MyValue::try_from(v) {
+                Ok(v) => named.push((sqlite_name.to_owned(), v)),
+                Err(message) => {
+                    return Err(PersistenceError::InvalidParamValue {
+                        name: name.into_owned(),
+                        message,
+                    })
+                }
+            },
+            None if is_auth_param(&name) => named.push((sqlite_name.to_owned(), MyValue::Null)),
+            None => missing.push(name.into_owned()),
+        }
+    }
+    let unexpected: Vec<String> = params.into_keys().filter(|k| !is_auth_param(k)).collect();
+    if !missing.is_empty() || !unexpected.is_empty() {
+        return Err(PersistenceError::InvalidParams {
+            missing,
+            unexpected,
+        });
+    }
+    Ok(named)
 }
 
 fn populate_policy(txn: &mut Transaction, policy: Policy) -> PersistenceResult<()> {
-    let mut stmt =
-        txn.prepare("INSERT INTO __ezdb_metadata__ (type, name, raw_sql) VALUES (?, ?, ?)")?;
+    let mut stmt = txn
+        .prepare("INSERT INTO __ezdb_metadata__ (type, name, raw_sql, rule, timeout_ms) VALUES (?, ?, ?, ?, ?)")?;
     for p in policy.queries {
-        stmt.execute(&["query", &p.name, &p.raw_sql])?;
+        stmt.execute(params![
+            "query",
+            p.name,
+            p.raw_sql,
+            p.rule.to_string(),
+            p.timeout_ms
+        ])?;
     }
     for p in policy.mutations {
-        stmt.execute(&["mutation", &p.name, &p.raw_sql])?;
+        stmt.execute(params![
+            "mutation",
+            p.name,
+            p.raw_sql,
+            p.rule.to_string(),
+            p.timeout_ms
+        ])?;
     }
     Ok(())
 }
 
-// TODO(rpb): try to optimize this so that it's serialized directly from the ValueRef.
-// Right now we're cloning the data just so that we can serialize it after the query completes.
 enum MyValue {
     Null,
     Integer(i64),
@@ -195,30 +1419,32 @@ enum MyValue {
     Text(String),
     Bytes(Vec<u8>),
 }
-impl Serialize for MyValue {
-    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        match self {
-            MyValue::Null => serializer.serialize_none(),
-            MyValue::Integer(i) => serializer.serialize_i64(*i),
-            MyValue::Float(i) => serializer.serialize_f64(*i),
-            MyValue::Text(i) => serializer.serialize_str(i),
-            MyValue::Bytes(i) => serializer.serialize_bytes(i),
-        }
-    }
-}
-
 impl FromSql for MyValue {
     fn column_result(value: ValueRef) -> Result<MyValue, FromSqlError> {
         Ok(match value {
             ValueRef::Null => MyValue::Null,
             ValueRef::Integer(i) => MyValue::Integer(i),
             ValueRef::Real(i) => MyValue::Float(i),
-            ValueRef::Text(i) => MyValue::Text(String::from_utf8(i.to_vec()).unwrap()),
+            // SQLite doesn't check that text is valid UTF-8, so text that isn't is
+            // returned as the bytes it is.
+            ValueRef::Text(i) => match std::str::from_utf8(i) {
+                Ok(text) => MyValue::Text(text.to_owned()),
+                Err(_) => MyValue::Bytes(i.to_vec()),
+            },
             ValueRef::Blob(i) => MyValue::Bytes(i.to_vec()),
         })
     }
 }
 
+impl FromSql for Rule {
+    fn column_result(value: ValueRef) -> Result<Rule, FromSqlError> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
+
 impl ToSql for MyValue {
     fn to_sql(&self) -> Result<ToSqlOutput<'_>, rusqlite::Error> {
         Ok(match self {
@@ -231,18 +1457,1274 @@ impl ToSql for MyValue {
     }
 }
 
-impl From<Value> for MyValue {
-    fn from(v: Value) -> MyValue {
-        match v {
+// Tagged values are decoded, and other arrays and objects are bound as JSON text, for
+// use with SQLite's JSON functions.
+impl TryFrom<Value> for MyValue {
+    type Error = String;
+    fn try_from(v: Value) -> Result<MyValue, String> {
+        match values::decode(&v) {
+            Some(Ok(Tagged::Blob(b))) => return Ok(MyValue::Bytes(b)),
+            Some(Ok(Tagged::Integer(i))) => return Ok(MyValue::Integer(i)),
+            Some(Ok(Tagged::Real(f))) => return Ok(MyValue::Float(f)),
+            Some(Err(e)) => return Err(e),
+            None => {}
+        }
+        Ok(match v {
             Value::Null => MyValue::Null,
             Value::Bool(b) => MyValue::Integer(if b { 1 } else { 0 }),
-            Value::Number(i) => i
-                .as_i64()
-                .map(MyValue::Integer)
-                .unwrap_or_else(|| MyValue::Float(i.as_f64().unwrap())),
-            Value::String(i) => MyValue::Text(i),
-            Value::Array(_) => panic!(),
-            Value::Object(_) => panic!(),
+            Value::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+                (Some(i), _, _) => MyValue::Integer(i),
+                (None, Some(u), _) => return Err(format!("integer {} is out of range", u)),
+                (None, None, Some(f)) => MyValue::Float(f),
+                (None, None, None) => return Err(format!("unsupported number {}", n)),
+            },
+            Value::String(s) => MyValue::Text(s),
+            v @ Value::Array(_) | v @ Value::Object(_) => MyValue::Text(v.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SqliteLimits, SqlitePersistence};
+    use crate::core::{
+        BatchStep, Migration, MutationPolicy, PageRequest, Policy, PolicyChange, QueryPolicy,
+    };
+    use crate::persistence::{Persistence, PersistenceError, RowFormat};
+    use crate::rules::Caller;
+    use crate::values::ValueEncoding;
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    fn people() -> SqlitePersistence {
+        let p = SqlitePersistence::in_memory().unwrap();
+        p.mutate_raw(
+            "CREATE TABLE person (id TEXT, name TEXT)".to_owned(),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+        p.set_policy(Policy {
+            queries: vec![QueryPolicy {
+                name: "mine".to_owned(),
+                raw_sql: "SELECT name FROM person WHERE id = :id OR id = :auth.uid".to_owned(),
+                rule: Default::default(),
+                timeout_ms: None,
+            }],
+            mutations: vec![MutationPolicy {
+                name: "add".to_owned(),
+                raw_sql: "INSERT INTO person (id, name) VALUES (:id, :name)".to_owned(),
+                rule: Default::default(),
+                timeout_ms: None,
+            }],
+        })
+        .unwrap();
+        p
+    }
+
+    fn anyone() -> Caller {
+        Caller::anonymous()
+    }
+
+    fn params(value: Value) -> BTreeMap<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn params_must_match_the_template() {
+        let p = people();
+        match p.mutate_named(
+            &anyone(),
+            "add".to_owned(),
+            params(json!({":id": "a", ":nmae": "x"})),
+            ValueEncoding::Typed,
+        ) {
+            Err(PersistenceError::InvalidParams {
+                missing,
+                unexpected,
+            }) => {
+                assert_eq!(missing, vec![":name"]);
+                assert_eq!(unexpected, vec![":nmae"]);
+            }
+            other => panic!("expected invalid params, got {:?}", other),
+        }
+        p.mutate_named(
+            &anyone(),
+            "add".to_owned(),
+            params(json!({":id": "a", ":name": "x"})),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn auth_params_are_optional() {
+        let p = people();
+        p.mutate_named(
+            &anyone(),
+            "add".to_owned(),
+            params(json!({":id": "a", ":name": "x"})),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+        // Claims that the template doesn't use are ignored, and missing ones bind NULL.
+        let rows = p
+            .query_named(
+                &anyone(),
+                "mine".to_owned(),
+                params(json!({":id": "b", ":auth.role": "admin"})),
+                ValueEncoding::Typed,
+            )
+            .unwrap();
+        assert_eq!(rows, json!([]));
+        let rows = p
+            .query_named(
+                &anyone(),
+                "mine".to_owned(),
+                params(json!({":id": "b", ":auth.uid": "a"})),
+                ValueEncoding::Typed,
+            )
+            .unwrap();
+        assert_eq!(rows, json!([{"name": "x"}]));
+    }
+
+    #[test]
+    fn arrays_and_objects_are_bound_as_json() {
+        let p = people();
+        for (id, name) in &[("a", "x"), ("b", "y"), ("c", "z")] {
+            p.mutate_named(
+                &anyone(),
+                "add".to_owned(),
+                params(json!({":id": id, ":name": name})),
+                ValueEncoding::Typed,
+            )
+            .unwrap();
+        }
+        p.set_policy(Policy {
+            queries: vec![
+                QueryPolicy {
+                    name: "some".to_owned(),
+                    raw_sql: "SELECT name FROM person WHERE id IN (:ids) ORDER BY id".to_owned(),
+                    rule: Default::default(),
+                    timeout_ms: None,
+                },
+                QueryPolicy {
+                    name: "by_filter".to_owned(),
+                    raw_sql: "SELECT name FROM person WHERE id = json_extract(:filter, '$.id')"
+                        .to_owned(),
+                    rule: Default::default(),
+                    timeout_ms: None,
+                },
+            ],
+            mutations: vec![],
+        })
+        .unwrap();
+
+        let some = |ids: Value| {
+            p.query_named(
+                &anyone(),
+                "some".to_owned(),
+                params(json!({ ":ids": ids })),
+                ValueEncoding::Typed,
+            )
+        };
+        assert_eq!(
+            some(json!(["c", "a", "nobody"])).unwrap(),
+            json!([{"name": "x"}, {"name": "z"}])
+        );
+        assert_eq!(some(json!([])).unwrap(), json!([]));
+        assert_eq!(some(json!("b")).unwrap(), json!([{"name": "y"}]));
+
+        let rows = p
+            .query_named(
+                &anyone(),
+                "by_filter".to_owned(),
+                params(json!({":filter": {"id": "b"}})),
+                ValueEncoding::Typed,
+            )
+            .unwrap();
+        assert_eq!(rows, json!([{"name": "y"}]));
+    }
+
+    #[test]
+    fn unbindable_values_are_rejected() {
+        let p = people();
+        let err = p
+            .mutate_named(
+                &anyone(),
+                "add".to_owned(),
+                params(json!({":id": u64::MAX, ":name": "x"})),
+                ValueEncoding::Typed,
+            )
+            .unwrap_err();
+        assert_eq!(
+            err,
+            PersistenceError::InvalidParamValue {
+                name: ":id".to_owned(),
+                message: format!("integer {} is out of range", u64::MAX),
+            }
+        );
+    }
+
+    #[test]
+    fn sqlite_errors_are_classified() {
+        let p = people();
+        let err = |sql: &str| {
+            p.mutate_raw(sql.to_owned(), ValueEncoding::Typed)
+                .unwrap_err()
+        };
+        assert!(matches!(err("SELEC 1"), PersistenceError::SyntaxError(_)));
+        assert!(matches!(
+            err("INSERT INTO people VALUES (1)"),
+            PersistenceError::SchemaError(_)
+        ));
+        assert!(matches!(
+            err("CREATE TABLE person (id TEXT)"),
+            PersistenceError::SchemaError(_)
+        ));
+        p.mutate_raw(
+            "CREATE UNIQUE INDEX person_id ON person (id)".to_owned(),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+        p.mutate_named(
+            &anyone(),
+            "add".to_owned(),
+            params(json!({":id": "a", ":name": "x"})),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+        assert!(matches!(
+            p.mutate_named(
+                &anyone(),
+                "add".to_owned(),
+                params(json!({":id": "a", ":name": "y"})),
+                ValueEncoding::Typed
+            ),
+            Err(PersistenceError::ConstraintViolation(_))
+        ));
+        assert!(matches!(
+            p.mutate_named(
+                &anyone(),
+                "nope".to_owned(),
+                BTreeMap::new(),
+                ValueEncoding::Typed
+            ),
+            Err(PersistenceError::NoSuchQuery(_))
+        ));
+    }
+
+    #[test]
+    fn rules_are_checked_with_their_template() {
+        let p = people();
+        let private = |rule: &str| {
+            PolicyChange::PutQuery(QueryPolicy {
+                name: "private".to_owned(),
+                raw_sql: "SELECT name FROM person".to_owned(),
+                rule: rule.parse().unwrap(),
+                timeout_ms: None,
+            })
+        };
+        let run = |caller: &Caller| {
+            p.query_named(
+                caller,
+                "private".to_owned(),
+                BTreeMap::new(),
+                ValueEncoding::Typed,
+            )
+        };
+        let user = Caller::authenticated(json!({"uid": "a"}).as_object().unwrap().clone());
+        p.update_policy(private("authenticated"), None, None)
+            .unwrap();
+        assert!(matches!(
+            run(&anyone()),
+            Err(PersistenceError::PermissionDenied(_))
+        ));
+        assert_eq!(run(&user), Ok(json!([])));
+        let step = serde_json::from_value(json!({"type": "query", "name": "private"})).unwrap();
+        assert!(matches!(
+            p.batch(&anyone(), vec![step], ValueEncoding::Typed),
+            Err(PersistenceError::BatchFailed { step: 0, cause })
+                if matches!(*cause, PersistenceError::PermissionDenied(_))
+        ));
+
+        // The rule that's checked is always the current one.
+        p.update_policy(private("anyone"), None, None).unwrap();
+        assert_eq!(run(&anyone()), Ok(json!([])));
+        assert_eq!(
+            p.query_named(
+                &anyone(),
+                "nope".to_owned(),
+                BTreeMap::new(),
+                ValueEncoding::Typed
+            ),
+            Err(PersistenceError::NoSuchQuery("nope".to_owned()))
+        );
+        // A template that can't be read is an error, not a missing template.
+        p.conn
+            .execute(
+                "UPDATE __ezdb_metadata__ SET rule = '&&' WHERE name = 'private'",
+                [],
+            )
+            .unwrap();
+        assert!(matches!(run(&anyone()), Err(PersistenceError::Unknown(_))));
+    }
+
+    #[test]
+    fn batches_are_atomic() {
+        let p = people();
+        let step = |value: Value| serde_json::from_value::<BatchStep>(value).unwrap();
+        let results = p
+            .batch(&anyone(), vec![
+                step(json!({"type": "mutation", "name": "add", "params": {":id": "a", ":name": "x"}})),
+                step(json!({"type": "query", "name": "mine", "params": {":id": "a"}})),
+            ], ValueEncoding::Typed)
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                json!({"changes": 1, "lastInsertRowid": 1, "rows": []}),
+                json!([{"name": "x"}]),
+            ]
+        );
+
+        let err = p
+            .batch(&anyone(), vec![
+                step(json!({"type": "mutation", "name": "add", "params": {":id": "b", ":name": "y"}})),
+                step(json!({"type": "mutation", "name": "add", "params": {":id": "c"}})),
+            ], ValueEncoding::Typed)
+            .unwrap_err();
+        assert!(matches!(err, PersistenceError::BatchFailed { step: 1, .. }));
+        let rows = p
+            .query_named(
+                &anyone(),
+                "mine".to_owned(),
+                params(json!({":id": "b"})),
+                ValueEncoding::Typed,
+            )
+            .unwrap();
+        assert_eq!(rows, json!([]));
+    }
+
+    #[test]
+    fn cancelled_jobs_stop_between_statements() {
+        let p = people();
+        let cancelled = p.get_cancel_flag();
+        cancelled.store(true, Ordering::Relaxed);
+        let step = |value: Value| serde_json::from_value::<BatchStep>(value).unwrap();
+        let err = p
+            .batch(&anyone(), vec![
+                step(json!({"type": "mutation", "name": "add", "params": {":id": "a", ":name": "x"}})),
+            ], ValueEncoding::Typed)
+            .unwrap_err();
+        assert_eq!(err, PersistenceError::Interrupted.in_step(0));
+        assert_eq!(
+            p.query_raw("SELECT 1".to_owned(), ValueEncoding::Typed),
+            Err(PersistenceError::Interrupted)
+        );
+
+        // A statement that's already running stops at its next progress check, even
+        // without a timeout.
+        cancelled.store(false, Ordering::Relaxed);
+        let flag = cancelled.clone();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            flag.store(true, Ordering::Relaxed);
+        });
+        let err = p.query_raw(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n) SELECT COUNT(1) FROM n"
+                .to_owned(), ValueEncoding::Typed);
+        canceller.join().unwrap();
+        assert_eq!(err, Err(PersistenceError::Interrupted));
+
+        cancelled.store(false, Ordering::Relaxed);
+        assert_eq!(
+            p.query_raw("SELECT 1 AS x".to_owned(), ValueEncoding::Typed),
+            Ok(json!([{"x": 1}]))
+        );
+    }
+
+    #[test]
+    fn mutations_report_what_they_did() {
+        let p = SqlitePersistence::in_memory().unwrap();
+        let raw = |sql: &str| {
+            serde_json::to_value(p.mutate_raw(sql.to_owned(), ValueEncoding::Typed).unwrap())
+                .unwrap()
+        };
+        assert_eq!(
+            raw("CREATE TABLE person (id INTEGER PRIMARY KEY, name TEXT)"),
+            json!({"changes": 0, "lastInsertRowid": 0, "rows": []})
+        );
+        assert_eq!(
+            raw("INSERT INTO person (name) VALUES ('x'), ('y')"),
+            json!({"changes": 2, "lastInsertRowid": 2, "rows": []})
+        );
+        // Statements that don't touch any rows report no changes.
+        assert_eq!(
+            raw("CREATE INDEX person_name ON person (name)"),
+            json!({"changes": 0, "lastInsertRowid": 2, "rows": []})
+        );
+
+        p.set_policy(Policy {
+            queries: vec![],
+            mutations: vec![MutationPolicy {
+                name: "add".to_owned(),
+                raw_sql:
+                    "INSERT INTO person (name) VALUES (:name) RETURNING id, upper(name) AS shout"
+                        .to_owned(),
+                rule: Default::default(),
+                timeout_ms: None,
+            }],
+        })
+        .unwrap();
+        let result = p
+            .mutate_named(
+                &anyone(),
+                "add".to_owned(),
+                params(json!({":name": "z"})),
+                ValueEncoding::Typed,
+            )
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(result).unwrap(),
+            json!({"changes": 1, "lastInsertRowid": 3, "rows": [{"id": 3, "shout": "Z"}]})
+        );
+
+        // Streamed mutations write their rows in the format asked for instead.
+        let mut out = Vec::new();
+        let counts = p
+            .stream_mutation_named(
+                &anyone(),
+                "add".to_owned(),
+                params(json!({":name": "w"})),
+                RowFormat::Ndjson,
+                ValueEncoding::Typed,
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(counts).unwrap(),
+            json!({"changes": 1, "lastInsertRowid": 4})
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"id\":4,\"shout\":\"W\"}\n"
+        );
+
+        // Returned rows are held in memory like a query's, so there can't be more of
+        // them, and a mutation that returns too many is undone.
+        let p = p.with_max_rows(1);
+        assert_eq!(
+            p.mutate_raw(
+                "DELETE FROM person RETURNING id".to_owned(),
+                ValueEncoding::Typed
+            )
+            .unwrap_err(),
+            PersistenceError::TooManyRows(1)
+        );
+        let err = p
+            .stream_mutation_raw(
+                "UPDATE person SET name = 'v' RETURNING id".to_owned(),
+                RowFormat::Csv,
+                ValueEncoding::Typed,
+                &mut Vec::new(),
+            )
+            .unwrap_err();
+        assert_eq!(err, PersistenceError::TooManyRows(1));
+        assert_eq!(
+            p.query_raw(
+                "SELECT count(*) AS n FROM person WHERE name <> 'v'".to_owned(),
+                ValueEncoding::Typed
+            )
+            .unwrap(),
+            json!([{"n": 4}])
+        );
+    }
+
+    #[test]
+    fn slow_statements_are_aborted() {
+        let p = people().with_default_timeout(Some(Duration::from_secs(60)));
+        let forever = "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n) \
+                       SELECT count(*) FROM n";
+        p.set_policy(Policy {
+            queries: vec![QueryPolicy {
+                name: "forever".to_owned(),
+                raw_sql: forever.to_owned(),
+                rule: Default::default(),
+                timeout_ms: Some(20),
+            }],
+            mutations: vec![],
+        })
+        .unwrap();
+        assert_eq!(
+            p.query_named(
+                &anyone(),
+                "forever".to_owned(),
+                BTreeMap::new(),
+                ValueEncoding::Typed
+            ),
+            Err(PersistenceError::DeadlineExceeded)
+        );
+
+        // The connection is still usable, and the template's timeout only applies to it.
+        let p = p.with_default_timeout(Some(Duration::from_millis(20)));
+        assert_eq!(
+            p.query_raw("SELECT 1 AS one".to_owned(), ValueEncoding::Typed)
+                .unwrap(),
+            json!([{"one": 1}])
+        );
+        assert_eq!(
+            p.query_raw(forever.to_owned(), ValueEncoding::Typed),
+            Err(PersistenceError::DeadlineExceeded)
+        );
+    }
+
+    #[test]
+    fn big_results_are_paged() {
+        let p = people().with_max_rows(3);
+        for id in &["a", "b", "c", "d", "e"] {
+            p.mutate_named(
+                &anyone(),
+                "add".to_owned(),
+                params(json!({":id": id, ":name": id})),
+                ValueEncoding::Typed,
+            )
+            .unwrap();
+        }
+        p.set_policy(Policy {
+            queries: vec![
+                QueryPolicy {
+                    name: "all".to_owned(),
+                    raw_sql: "SELECT id FROM person ORDER BY id".to_owned(),
+                    rule: Default::default(),
+                    timeout_ms: None,
+                },
+                QueryPolicy {
+                    name: "after".to_owned(),
+                    raw_sql: "SELECT id FROM person WHERE id > :id ORDER BY id".to_owned(),
+                    rule: Default::default(),
+                    timeout_ms: None,
+                },
+                QueryPolicy {
+                    name: "unordered".to_owned(),
+                    raw_sql: "SELECT id FROM person".to_owned(),
+                    rule: Default::default(),
+                    timeout_ms: None,
+                },
+            ],
+            mutations: vec![],
+        })
+        .unwrap();
+        assert_eq!(
+            p.query_named(
+                &anyone(),
+                "all".to_owned(),
+                BTreeMap::new(),
+                ValueEncoding::Typed
+            ),
+            Err(PersistenceError::TooManyRows(3))
+        );
+
+        let page = |limit: Option<usize>, page_token: Option<String>| {
+            p.query_page(
+                &anyone(),
+                "all".to_owned(),
+                BTreeMap::new(),
+                PageRequest { limit, page_token },
+                ValueEncoding::Typed,
+            )
+            .unwrap()
+        };
+        let first = page(Some(2), None);
+        assert_eq!(first.rows, json!([{"id": "a"}, {"id": "b"}]));
+        // The server's maximum caps the requested limit.
+        let second = page(Some(100), first.next_page_token);
+        assert_eq!(second.rows, json!([{"id": "c"}, {"id": "d"}, {"id": "e"}]));
+        assert_eq!(second.next_page_token, None);
+
+        // Streamed pages hold the same rows, and their tokens work the same way.
+        let mut out = Vec::new();
+        let token = p
+            .stream_named(
+                &anyone(),
+                "all".to_owned(),
+                BTreeMap::new(),
+                Some(PageRequest {
+                    limit: Some(2),
+                    page_token: None,
+                }),
+                RowFormat::Ndjson,
+                ValueEncoding::Typed,
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"id\":\"a\"}\n{\"id\":\"b\"}\n"
+        );
+        assert_eq!(token, page(Some(2), None).next_page_token);
+        assert_eq!(page(Some(100), token).rows, second.rows);
+
+        // A token can't be changed to start somewhere else, since it's signed.
+        let token = page(Some(1), None).next_page_token.unwrap();
+        let (json, tag) = token.split_once('.').unwrap();
+        let json = base64::decode_config(json, base64::URL_SAFE_NO_PAD).unwrap();
+        let json = String::from_utf8(json)
+            .unwrap()
+            .replace("\"offset\":1", "\"offset\":3");
+        let forged = format!(
+            "{}.{}",
+            base64::encode_config(json, base64::URL_SAFE_NO_PAD),
+            tag
+        );
+        assert_eq!(
+            p.query_page(
+                &anyone(),
+                "all".to_owned(),
+                BTreeMap::new(),
+                PageRequest {
+                    limit: None,
+                    page_token: Some(forged),
+                },
+                ValueEncoding::Typed,
+            )
+            .unwrap_err(),
+            PersistenceError::InvalidArgument("invalid page token".to_owned())
+        );
+
+        // Queries that don't order their rows can't be paged at all.
+        assert_eq!(
+            p.query_page(
+                &anyone(),
+                "unordered".to_owned(),
+                BTreeMap::new(),
+                PageRequest::default(),
+                ValueEncoding::Typed,
+            )
+            .unwrap_err(),
+            PersistenceError::InvalidArgument(
+                "query unordered has no ORDER BY, so it can't be paged".to_owned()
+            )
+        );
+
+        let err = p
+            .query_page(
+                &anyone(),
+                "after".to_owned(),
+                params(json!({":id": "a"})),
+                PageRequest {
+                    limit: None,
+                    page_token: page(Some(1), None).next_page_token,
+                },
+                ValueEncoding::Typed,
+            )
+            .unwrap_err();
+        assert_eq!(
+            err,
+            PersistenceError::InvalidArgument("invalid page token".to_owned())
+        );
+
+        // Nor can a token be used with different params.
+        let after = |id: &str, page_token: Option<String>| {
+            p.query_page(
+                &anyone(),
+                "after".to_owned(),
+                params(json!({":id": id})),
+                PageRequest {
+                    limit: Some(1),
+                    page_token,
+                },
+                ValueEncoding::Typed,
+            )
+        };
+        let token = after("a", None).unwrap().next_page_token;
+        assert_eq!(
+            after("a", token.clone()).unwrap().rows,
+            json!([{"id": "c"}])
+        );
+        assert_eq!(
+            after("b", token).unwrap_err(),
+            PersistenceError::InvalidArgument("invalid page token".to_owned())
+        );
+    }
+
+    #[test]
+    fn streamed_rows_match_collected_ones() {
+        let p = people();
+        for (id, name) in &[("a", "x"), ("b", "y")] {
+            p.mutate_named(
+                &anyone(),
+                "add".to_owned(),
+                params(json!({":id": id, ":name": name})),
+                ValueEncoding::Typed,
+            )
+            .unwrap();
+        }
+        let stream = |format: RowFormat| {
+            let mut out = Vec::new();
+            p.stream_raw(
+                "SELECT id, name, NULL AS missing FROM person ORDER BY id".to_owned(),
+                format,
+                ValueEncoding::Typed,
+                &mut out,
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let rows = p
+            .query_raw(
+                "SELECT id, name, NULL AS missing FROM person ORDER BY id".to_owned(),
+                ValueEncoding::Typed,
+            )
+            .unwrap();
+        let streamed: Value = serde_json::from_str(&stream(RowFormat::Json)).unwrap();
+        assert_eq!(streamed, rows);
+        assert_eq!(
+            stream(RowFormat::Ndjson),
+            "{\"id\":\"a\",\"name\":\"x\",\"missing\":null}\n\
+             {\"id\":\"b\",\"name\":\"y\",\"missing\":null}\n"
+        );
+
+        // Nothing is written for a query that fails.
+        let mut out = Vec::new();
+        let err = p
+            .stream_named(
+                &anyone(),
+                "nope".to_owned(),
+                BTreeMap::new(),
+                None,
+                RowFormat::Json,
+                ValueEncoding::Typed,
+                &mut out,
+            )
+            .unwrap_err();
+        assert_eq!(err, PersistenceError::NoSuchQuery("nope".to_owned()));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn typed_values_round_trip() {
+        let p = SqlitePersistence::in_memory().unwrap();
+        p.mutate_raw(
+            "CREATE TABLE thing (data BLOB, n INTEGER, x REAL)".to_owned(),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+        p.set_policy(Policy {
+            queries: vec![QueryPolicy {
+                name: "all".to_owned(),
+                raw_sql: "SELECT * FROM thing".to_owned(),
+                rule: Default::default(),
+                timeout_ms: None,
+            }],
+            mutations: vec![MutationPolicy {
+                name: "add".to_owned(),
+                raw_sql: "INSERT INTO thing VALUES (:data, :n, :x)".to_owned(),
+                rule: Default::default(),
+                timeout_ms: None,
+            }],
+        })
+        .unwrap();
+        let row = json!({
+            ":data": {"$blob": "AAH/"},
+            ":n": {"$int": "9007199254740993"},
+            ":x": {"$real": "-Infinity"},
+        });
+        p.mutate_named(
+            &anyone(),
+            "add".to_owned(),
+            params(row),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+        assert_eq!(
+            p.query_named(
+                &anyone(),
+                "all".to_owned(),
+                BTreeMap::new(),
+                ValueEncoding::Typed
+            )
+            .unwrap(),
+            json!([{
+                "data": {"$blob": "AAH/"},
+                "n": {"$int": "9007199254740993"},
+                "x": {"$real": "-Infinity"},
+            }])
+        );
+
+        let err = p
+            .mutate_named(
+                &anyone(),
+                "add".to_owned(),
+                params(json!({":data": {"$blob": "!"}, ":n": 1, ":x": 1})),
+                ValueEncoding::Typed,
+            )
+            .unwrap_err();
+        assert!(matches!(err, PersistenceError::InvalidParamValue { .. }));
+
+        // Plain results have numbers and byte arrays instead, and a column that's named
+        // like a tag is left alone.
+        assert_eq!(
+            p.query_named(
+                &anyone(),
+                "all".to_owned(),
+                BTreeMap::new(),
+                ValueEncoding::Plain
+            )
+            .unwrap(),
+            json!([{"data": [0, 1, 255], "n": 9007199254740993i64, "x": null}])
+        );
+        assert_eq!(
+            p.query_raw(
+                "SELECT data AS \"$blob\" FROM thing".to_owned(),
+                ValueEncoding::Plain
+            )
+            .unwrap(),
+            json!([{"$blob": [0, 1, 255]}])
+        );
+        let result = p
+            .mutate_raw(
+                "UPDATE thing SET n = n + 1 RETURNING n".to_owned(),
+                ValueEncoding::Plain,
+            )
+            .unwrap();
+        assert_eq!(result.rows, json!([{"n": 9007199254740994i64}]));
+    }
+
+    #[test]
+    fn invalid_text_is_returned_as_bytes() {
+        let p = SqlitePersistence::in_memory().unwrap();
+        p.mutate_raw(
+            "CREATE TABLE note (body TEXT)".to_owned(),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+        p.mutate_raw(
+            "INSERT INTO note VALUES ('ok'), (CAST(x'ff00fe' AS TEXT))".to_owned(),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+        let query = "SELECT body FROM note ORDER BY rowid".to_owned();
+        let expected = json!([{"body": "ok"}, {"body": {"$blob": "/wD+"}}]);
+        assert_eq!(
+            p.query_raw(query.clone(), ValueEncoding::Typed).unwrap(),
+            expected
+        );
+
+        let mut out = Vec::new();
+        p.stream_raw(query, RowFormat::Json, ValueEncoding::Typed, &mut out)
+            .unwrap();
+        assert_eq!(serde_json::from_slice::<Value>(&out).unwrap(), expected);
+    }
+
+    #[test]
+    fn templates_change_one_at_a_time() {
+        let p = people();
+        let version = p.fetch_policy().unwrap().version;
+        let query = |name: &str| QueryPolicy {
+            name: name.to_owned(),
+            raw_sql: "SELECT name FROM person".to_owned(),
+            rule: Default::default(),
+            timeout_ms: None,
+        };
+        assert_eq!(
+            p.update_policy(
+                PolicyChange::PutQuery(query("everyone")),
+                Some(version),
+                None
+            ),
+            Ok(version + 1)
+        );
+        // A change made against an older version is refused.
+        assert_eq!(
+            p.update_policy(
+                PolicyChange::DeleteQuery("mine".to_owned()),
+                Some(version),
+                None
+            ),
+            Err(PersistenceError::VersionMismatch {
+                expected: version,
+                actual: version + 1,
+            })
+        );
+        assert_eq!(
+            p.update_policy(PolicyChange::DeleteQuery("mine".to_owned()), None, None),
+            Ok(version + 2)
+        );
+        let policy = p.fetch_policy().unwrap();
+        assert_eq!(policy.version, version + 2);
+        let names: Vec<_> = policy.policy.queries.iter().map(|q| &q.name).collect();
+        assert_eq!(names, vec!["everyone"]);
+        assert_eq!(policy.policy.mutations.len(), 1);
+
+        // Changes to one template are still checked against the schema.
+        let mut bad = query("bad");
+        bad.raw_sql = "SELECT nope FROM person".to_owned();
+        assert!(matches!(
+            p.update_policy(PolicyChange::PutQuery(bad), None, None),
+            Err(PersistenceError::InvalidPolicy(_))
+        ));
+        assert_eq!(
+            p.update_policy(PolicyChange::DeleteMutation("nope".to_owned()), None, None),
+            Err(PersistenceError::NoSuchQuery("nope".to_owned()))
+        );
+        assert_eq!(p.fetch_policy().unwrap().version, version + 2);
+    }
+
+    #[test]
+    fn policies_can_be_rolled_back() {
+        let p = people();
+        let original = p.fetch_policy().unwrap();
+        p.update_policy(
+            PolicyChange::DeleteQuery("mine".to_owned()),
+            None,
+            Some("abc".to_owned()),
+        )
+        .unwrap();
+        let history = p.policy_history().unwrap();
+        let revisions: Vec<_> = history.iter().map(|r| r.revision).collect();
+        assert_eq!(revisions, vec![original.version, original.version + 1]);
+        assert_eq!(history[1].author.as_deref(), Some("abc"));
+        assert!(history[1].policy.is_none());
+
+        // Rolling back makes a new revision, with the old one's templates.
+        let version = p
+            .update_policy(PolicyChange::Rollback(original.version), None, None)
+            .unwrap();
+        assert_eq!(version, original.version + 2);
+        let revision = p.fetch_policy_revision(version).unwrap();
+        assert_eq!(
+            serde_json::to_value(revision.policy).unwrap(),
+            serde_json::to_value(original.policy).unwrap()
+        );
+        assert_eq!(p.fetch_policy().unwrap().policy.queries[0].name, "mine");
+
+        assert_eq!(
+            p.update_policy(PolicyChange::Rollback(99), None, None),
+            Err(PersistenceError::NoSuchRevision(99))
+        );
+        assert_eq!(
+            p.fetch_policy_revision(0).unwrap_err(),
+            PersistenceError::NoSuchRevision(0)
+        );
+    }
+
+    #[test]
+    fn migrations_are_applied_once_in_order() {
+        let p = people();
+        let migration = |name: &str, sql: &str| Migration {
+            name: name.to_owned(),
+            sql: sql.to_owned(),
+        };
+        let first = || migration("001_pets", "CREATE TABLE pet (name TEXT)");
+        let second = || migration("002_owner", "ALTER TABLE pet ADD COLUMN owner TEXT");
+        let applied = |p: &SqlitePersistence| -> Vec<String> {
+            p.list_migrations()
+                .unwrap()
+                .into_iter()
+                .map(|m| m.name)
+                .collect()
+        };
+
+        let report = p.apply_migrations(vec![first(), second()], true).unwrap();
+        assert_eq!(report.applied, vec!["001_pets", "002_owner"]);
+        assert!(applied(&p).is_empty());
+        assert!(p
+            .query_raw("SELECT * FROM pet".to_owned(), ValueEncoding::Typed)
+            .is_err());
+
+        p.apply_migrations(vec![first()], false).unwrap();
+        let report = p.apply_migrations(vec![first(), second()], false).unwrap();
+        assert_eq!(report.applied, vec!["002_owner"]);
+        assert_eq!(applied(&p), vec!["001_pets", "002_owner"]);
+        p.query_raw(
+            "SELECT name, owner FROM pet".to_owned(),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+
+        // History that's been edited, or left out, is refused.
+        let edited = migration("001_pets", "CREATE TABLE pet (name TEXT, age INTEGER)");
+        assert!(matches!(
+            p.apply_migrations(vec![edited, second()], false),
+            Err(PersistenceError::MigrationConflict { name, .. }) if name == "001_pets"
+        ));
+        assert!(matches!(
+            p.apply_migrations(vec![second()], false),
+            Err(PersistenceError::MigrationConflict { .. })
+        ));
+
+        // A failing migration takes the ones before it down with it.
+        let err = p
+            .apply_migrations(
+                vec![
+                    first(),
+                    second(),
+                    migration("003_toys", "CREATE TABLE toy (name TEXT)"),
+                    migration("004_oops", "DROP TABLE person"),
+                ],
+                false,
+            )
+            .unwrap_err();
+        // Dropping `person` would break the policy's templates.
+        assert!(matches!(err, PersistenceError::InvalidPolicy(_)));
+        assert_eq!(applied(&p), vec!["001_pets", "002_owner"]);
+        assert!(p
+            .query_raw("SELECT * FROM toy".to_owned(), ValueEncoding::Typed)
+            .is_err());
+
+        // Nor can a migration commit the ones before it by itself, or roll them back.
+        for sql in &[
+            "CREATE TABLE toy (name TEXT); COMMIT; CREATE TABLE game (name TEXT)",
+            "SAVEPOINT toys; CREATE TABLE toy (name TEXT); RELEASE toys",
+            "ROLLBACK",
+        ] {
+            let err = p
+                .apply_migrations(vec![first(), second(), migration("003_toys", sql)], false)
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                PersistenceError::BatchFailed { step: 2, cause }
+                    if matches!(*cause, PersistenceError::PermissionDenied(_))
+            ));
+            assert_eq!(applied(&p), vec!["001_pets", "002_owner"]);
+            assert!(p
+                .query_raw("SELECT * FROM toy".to_owned(), ValueEncoding::Typed)
+                .is_err());
+        }
+        // The connection is still in autocommit mode afterwards.
+        p.apply_migrations(
+            vec![
+                first(),
+                second(),
+                migration("003_toys", "CREATE TABLE toy (name TEXT)"),
+            ],
+            false,
+        )
+        .unwrap();
+        assert!(p.conn.is_autocommit());
+    }
+
+    #[test]
+    fn internal_tables_are_off_limits() {
+        let p = people();
+        fn denied<T>(result: Result<T, PersistenceError>) -> bool {
+            matches!(result, Err(PersistenceError::PermissionDenied(_)))
+        }
+        assert!(denied(p.query_raw(
+            "SELECT * FROM __ezdb_metadata__".to_owned(),
+            ValueEncoding::Typed
+        )));
+        let mut out = Vec::new();
+        assert!(denied(p.stream_raw(
+            "SELECT * FROM __ezdb_metadata__".to_owned(),
+            RowFormat::Json,
+            ValueEncoding::Typed,
+            &mut out
+        )));
+        assert!(denied(p.mutate_raw(
+            "UPDATE __EZDB_METADATA__ SET raw_sql = 'SELECT 1'".to_owned(),
+            ValueEncoding::Typed
+        )));
+        assert!(denied(p.mutate_raw(
+            "DROP TABLE __ezdb_policy_revisions__".to_owned(),
+            ValueEncoding::Typed
+        )));
+        // Nor hidden behind a temporary table of the same name.
+        assert!(denied(
+            p.mutate_raw(
+                "CREATE TEMP TABLE __ezdb_metadata__ (type, name, raw_sql, rule, timeout_ms)"
+                    .to_owned(),
+                ValueEncoding::Typed
+            )
+        ));
+        // Nor can a view be used to get at them, since it's checked when it's used.
+        p.mutate_raw(
+            "CREATE VIEW revisions AS SELECT * FROM __ezdb_policy_revisions__".to_owned(),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+        assert!(denied(p.query_raw(
+            "SELECT * FROM revisions".to_owned(),
+            ValueEncoding::Typed
+        )));
+        let migration = Migration {
+            name: "001_oops".to_owned(),
+            sql: "DELETE FROM __ezdb_migrations__".to_owned(),
+        };
+        assert!(matches!(
+            p.apply_migrations(vec![migration], false),
+            Err(PersistenceError::BatchFailed { step: 0, cause })
+                if matches!(*cause, PersistenceError::PermissionDenied(_))
+        ));
+        // Everything else is still up to admins.
+        p.mutate_raw(
+            "CREATE TABLE pet (name TEXT)".to_owned(),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+
+        let mutation = |raw_sql: &str| {
+            PolicyChange::PutMutation(MutationPolicy {
+                name: "sneaky".to_owned(),
+                raw_sql: raw_sql.to_owned(),
+                rule: Default::default(),
+                timeout_ms: None,
+            })
+        };
+        for raw_sql in &[
+            "DELETE FROM __ezdb_metadata__",
+            "ATTACH DATABASE '/tmp/other.db' AS other",
+            "PRAGMA journal_mode = DELETE",
+            "PRAGMA user_version = 2",
+            // Some pragmas do things, or give things away, without an argument too.
+            "PRAGMA database_list",
+            "PRAGMA wal_checkpoint",
+            "PRAGMA optimize",
+            "PRAGMA shrink_memory",
+            "SELECT load_extension('evil')",
+            "DROP TABLE person",
+        ] {
+            assert!(
+                matches!(
+                    p.update_policy(mutation(raw_sql), None, None),
+                    Err(PersistenceError::InvalidPolicy(_))
+                ),
+                "{}",
+                raw_sql
+            );
+        }
+        p.update_policy(mutation("DELETE FROM pet"), None, None)
+            .unwrap();
+        p.update_policy(
+            PolicyChange::PutQuery(QueryPolicy {
+                name: "columns".to_owned(),
+                raw_sql: "SELECT name FROM pragma_table_info('person')".to_owned(),
+                rule: Default::default(),
+                timeout_ms: None,
+            }),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            p.query_named(
+                &anyone(),
+                "columns".to_owned(),
+                params(json!({})),
+                ValueEncoding::Typed
+            ),
+            Ok(json!([{"name": "id"}, {"name": "name"}]))
+        );
+        p.update_policy(
+            PolicyChange::PutQuery(QueryPolicy {
+                name: "version".to_owned(),
+                raw_sql: "PRAGMA user_version".to_owned(),
+                rule: Default::default(),
+                timeout_ms: None,
+            }),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            p.query_named(
+                &anyone(),
+                "version".to_owned(),
+                params(json!({})),
+                ValueEncoding::Typed
+            ),
+            Ok(json!([{"user_version": 0}]))
+        );
+    }
+
+    #[test]
+    fn hardened_connections_stay_in_their_file() {
+        let dir = std::env::temp_dir().join(format!("ezdb-sqlite-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let other = dir.join("other.sqlite");
+        let copy = dir.join("copy.sqlite");
+        let _ = std::fs::remove_file(&copy);
+
+        let p = people()
+            .harden(&SqliteLimits {
+                like_pattern_length: 10,
+                ..Default::default()
+            })
+            .unwrap();
+        let attach = format!("ATTACH DATABASE '{}' AS other", other.display());
+        assert!(matches!(
+            p.mutate_raw(attach.clone(), ValueEncoding::Typed),
+            Err(PersistenceError::PermissionDenied(_))
+        ));
+        assert!(p
+            .mutate_raw(
+                "ATTACH DATABASE 'other' || '.sqlite' AS other".to_owned(),
+                ValueEncoding::Typed
+            )
+            .is_err());
+        assert!(p
+            .mutate_raw(
+                format!("VACUUM INTO '{}'", copy.display()),
+                ValueEncoding::Typed
+            )
+            .is_err());
+        assert!(!copy.exists());
+        assert!(p
+            .query_raw(
+                "SELECT load_extension('evil')".to_owned(),
+                ValueEncoding::Typed
+            )
+            .is_err());
+        p.mutate_raw("VACUUM".to_owned(), ValueEncoding::Typed)
+            .unwrap();
+        assert!(p
+            .query_raw(
+                "SELECT 'abc' LIKE '%%%%%%%%%%%%'".to_owned(),
+                ValueEncoding::Typed
+            )
+            .is_err());
+        assert!(matches!(
+            p.query_raw("SELECT zeroblob(20000000)".to_owned(), ValueEncoding::Typed),
+            Err(PersistenceError::InvalidArgument(_))
+        ));
+
+        // Without hardening, admins can do all of that.
+        let p = people();
+        p.mutate_raw("VACUUM".to_owned(), ValueEncoding::Typed)
+            .unwrap();
+        p.mutate_raw(attach, ValueEncoding::Typed).unwrap();
+        p.mutate_raw(
+            format!("VACUUM INTO '{}'", copy.display()),
+            ValueEncoding::Typed,
+        )
+        .unwrap();
+        assert!(copy.exists());
+
+        // But not get at ezdb's tables by attaching the database's own file, whatever
+        // they call it.
+        let path = dir.join("main.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let p = SqlitePersistence::from_file(&path).unwrap();
+        for name in &["x", "vacuum_db"] {
+            p.mutate_raw(
+                format!("ATTACH DATABASE '{}' AS {}", path.display(), name),
+                ValueEncoding::Typed,
+            )
+            .unwrap();
+            assert!(matches!(
+                p.mutate_raw(
+                    format!("DELETE FROM {}.__ezdb_metadata__", name),
+                    ValueEncoding::Typed
+                ),
+                Err(PersistenceError::PermissionDenied(_))
+            ));
+            p.mutate_raw(format!("DETACH DATABASE {}", name), ValueEncoding::Typed)
+                .unwrap();
         }
+        p.mutate_raw("VACUUM".to_owned(), ValueEncoding::Typed)
+            .unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
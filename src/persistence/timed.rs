@@ -1,11 +1,20 @@
 use crate::{
-    core::Policy,
-    persistence::{Persistence, PersistenceResult},
+    analyzer::Schema,
+    core::{BatchStep, Migration, PageRequest, PolicyChange},
+    persistence::{
+        AppliedMigration, MigrationReport, MutationCounts, MutationResult, Persistence,
+        PersistenceResult, PolicyRevision, QueryPage, RowFormat, VersionedPolicy,
+    },
+    rules::Caller,
+    values::ValueEncoding,
 };
 use log::trace;
 use rusqlite::InterruptHandle;
 use serde_json::Value;
 use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 pub struct Timed<P: Persistence>(P);
 impl<P: Persistence> Timed<P> {
@@ -32,27 +41,129 @@ impl<P: Persistence> Persistence for Timed<P> {
     #[track_caller]
     fn query_named(
         &self,
+        caller: &Caller,
         name: String,
         params: BTreeMap<String, Value>,
+        encoding: ValueEncoding,
     ) -> PersistenceResult<Value> {
-        timed!(self.0.query_named(name, params))
+        timed!(self.0.query_named(caller, name, params, encoding))
     }
-    fn mutate_named(&self, name: String, params: BTreeMap<String, Value>) -> PersistenceResult<()> {
-        timed!(self.0.mutate_named(name, params))
+    fn query_page(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        page: PageRequest,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<QueryPage> {
+        timed!(self.0.query_page(caller, name, params, page, encoding))
+    }
+    fn stream_named(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        page: Option<PageRequest>,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<Option<String>> {
+        timed!(self
+            .0
+            .stream_named(caller, name, params, page, format, encoding, out))
+    }
+    fn mutate_named(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<MutationResult> {
+        timed!(self.0.mutate_named(caller, name, params, encoding))
+    }
+    fn stream_mutation_named(
+        &self,
+        caller: &Caller,
+        name: String,
+        params: BTreeMap<String, Value>,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<MutationCounts> {
+        timed!(self
+            .0
+            .stream_mutation_named(caller, name, params, format, encoding, out))
     }
-    fn query_raw(&self, query: String) -> PersistenceResult<Value> {
-        timed!(self.0.query_raw(query))
+    fn batch(
+        &self,
+        caller: &Caller,
+        steps: Vec<BatchStep>,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<Vec<Value>> {
+        timed!(self.0.batch(caller, steps, encoding))
+    }
+    fn query_raw(&self, query: String, encoding: ValueEncoding) -> PersistenceResult<Value> {
+        timed!(self.0.query_raw(query, encoding))
+    }
+    fn stream_raw(
+        &self,
+        query: String,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<()> {
+        timed!(self.0.stream_raw(query, format, encoding, out))
     }
-    fn mutate_raw(&self, stmt: String) -> PersistenceResult<()> {
-        timed!(self.0.mutate_raw(stmt))
+    fn mutate_raw(
+        &self,
+        stmt: String,
+        encoding: ValueEncoding,
+    ) -> PersistenceResult<MutationResult> {
+        timed!(self.0.mutate_raw(stmt, encoding))
+    }
+    fn stream_mutation_raw(
+        &self,
+        stmt: String,
+        format: RowFormat,
+        encoding: ValueEncoding,
+        out: &mut dyn Write,
+    ) -> PersistenceResult<MutationCounts> {
+        timed!(self.0.stream_mutation_raw(stmt, format, encoding, out))
     }
-    fn fetch_policy(&self) -> PersistenceResult<Policy> {
+    fn fetch_policy(&self) -> PersistenceResult<VersionedPolicy> {
         timed!(self.0.fetch_policy())
     }
-    fn set_policy(&self, policy: Policy) -> PersistenceResult<()> {
-        timed!(self.0.set_policy(policy))
+    fn update_policy(
+        &self,
+        change: PolicyChange,
+        if_version: Option<u64>,
+        author: Option<String>,
+    ) -> PersistenceResult<u64> {
+        timed!(self.0.update_policy(change, if_version, author))
+    }
+    fn policy_history(&self) -> PersistenceResult<Vec<PolicyRevision>> {
+        timed!(self.0.policy_history())
+    }
+    fn fetch_policy_revision(&self, revision: u64) -> PersistenceResult<PolicyRevision> {
+        timed!(self.0.fetch_policy_revision(revision))
+    }
+    fn fetch_schema(&self) -> PersistenceResult<Schema> {
+        timed!(self.0.fetch_schema())
+    }
+    fn list_migrations(&self) -> PersistenceResult<Vec<AppliedMigration>> {
+        timed!(self.0.list_migrations())
+    }
+    fn apply_migrations(
+        &self,
+        migrations: Vec<Migration>,
+        dry_run: bool,
+    ) -> PersistenceResult<MigrationReport> {
+        timed!(self.0.apply_migrations(migrations, dry_run))
     }
     fn get_interrupt_handle(&self) -> InterruptHandle {
         timed!(self.0.get_interrupt_handle())
     }
+    fn get_cancel_flag(&self) -> Arc<AtomicBool> {
+        self.0.get_cancel_flag()
+    }
 }
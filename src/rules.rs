@@ -0,0 +1,386 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::{fmt, str::FromStr};
+
+/// `Caller` describes the end user making a request against a named template.
+#[derive(Debug, Default)]
+pub struct Caller {
+    claims: Option<Map<String, Value>>,
+}
+impl Caller {
+    pub fn anonymous() -> Caller {
+        Caller { claims: None }
+    }
+    pub fn authenticated(claims: Map<String, Value>) -> Caller {
+        Caller {
+            claims: Some(claims),
+        }
+    }
+    pub fn claims(&self) -> Option<&Map<String, Value>> {
+        self.claims.as_ref()
+    }
+}
+
+/// A `Rule` decides whether a caller may run a template with a given set of params.
+///
+/// Rules are small boolean expressions, for example:
+///   - `anyone`
+///   - `authenticated`
+///   - `auth.uid == :owner`
+///   - `authenticated && (auth.role == 'admin' || auth.uid == :id)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    source: String,
+    expr: Expr,
+}
+impl Rule {
+    pub fn anyone() -> Rule {
+        "anyone".parse().expect("valid rule")
+    }
+
+    pub fn allows(&self, caller: &Caller, params: &BTreeMap<String, Value>) -> bool {
+        self.expr.eval(caller, params).is_truthy()
+    }
+}
+impl Default for Rule {
+    fn default() -> Rule {
+        Rule::anyone()
+    }
+}
+impl FromStr for Rule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_or()?;
+        if let Some(tok) = parser.peek() {
+            return Err(format!("unexpected {:?} in rule: {}", tok, s));
+        }
+        Ok(Rule {
+            source: s.to_owned(),
+            expr,
+        })
+    }
+}
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+impl Serialize for Rule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+impl<'de> Deserialize<'de> for Rule {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Anyone,
+    Authenticated,
+    Literal(Value),
+    Claim(Vec<String>),
+    Param(String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Eq(Box<Expr>, Box<Expr>),
+    Ne(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, caller: &Caller, params: &BTreeMap<String, Value>) -> Value {
+        match self {
+            Expr::Anyone => Value::Bool(true),
+            Expr::Authenticated => Value::Bool(caller.claims.is_some()),
+            Expr::Literal(v) => v.clone(),
+            Expr::Claim(path) => {
+                let mut cur = match caller.claims {
+                    Some(ref claims) => claims.get(&path[0]),
+                    None => None,
+                };
+                for field in &path[1..] {
+                    cur = cur.and_then(|v| v.get(field));
+                }
+                cur.cloned().unwrap_or(Value::Null)
+            }
+            Expr::Param(name) => params.get(name).cloned().unwrap_or(Value::Null),
+            Expr::Not(e) => Value::Bool(!e.eval(caller, params).is_truthy()),
+            Expr::And(a, b) => Value::Bool(
+                a.eval(caller, params).is_truthy() && b.eval(caller, params).is_truthy(),
+            ),
+            Expr::Or(a, b) => Value::Bool(
+                a.eval(caller, params).is_truthy() || b.eval(caller, params).is_truthy(),
+            ),
+            Expr::Eq(a, b) => Value::Bool(loosely_equal(
+                &a.eval(caller, params),
+                &b.eval(caller, params),
+            )),
+            Expr::Ne(a, b) => Value::Bool(!loosely_equal(
+                &a.eval(caller, params),
+                &b.eval(caller, params),
+            )),
+        }
+    }
+}
+
+trait Truthy {
+    fn is_truthy(&self) -> bool;
+}
+impl Truthy for Value {
+    fn is_truthy(&self) -> bool {
+        *self == Value::Bool(true)
+    }
+}
+
+// Missing claims and params evaluate to null, and null is never equal to anything.
+// Otherwise `auth.uid == :owner` would let an anonymous caller through with no `:owner`.
+fn loosely_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Null, _) | (_, Value::Null) => false,
+        (Value::Number(x), Value::Number(y)) => x.as_f64() == y.as_f64(),
+        _ => a == b,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Param(String),
+    Str(String),
+    Num(f64),
+    Dot,
+    Not,
+    And,
+    Or,
+    Eq,
+    Ne,
+    LParen,
+    RParen,
+}
+
+fn tokenize(raw: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = raw.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            _ if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '.' => {
+                tokens.push(Token::Dot);
+                i += 1;
+            }
+            '&' | '|' | '=' if chars.get(i + 1) == Some(&c) => {
+                tokens.push(match c {
+                    '&' => Token::And,
+                    '|' => Token::Or,
+                    _ => Token::Eq,
+                });
+                i += 2;
+            }
+            '!' if chars.get(i + 1) == Some(&'=') => {
+                tokens.push(Token::Ne);
+                i += 2;
+            }
+            '!' => {
+                tokens.push(Token::Not);
+                i += 1;
+            }
+            '\'' | '"' => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&d| d == c)
+                    .ok_or_else(|| format!("unterminated string in rule: {}", raw))?;
+                tokens.push(Token::Str(chars[i + 1..i + 1 + end].iter().collect()));
+                i += end + 2;
+            }
+            ':' => {
+                let len = ident_len(&chars[i + 1..]);
+                if len == 0 {
+                    return Err(format!("expected a param name after ':' in rule: {}", raw));
+                }
+                tokens.push(Token::Param(chars[i..i + 1 + len].iter().collect()));
+                i += len + 1;
+            }
+            _ if c.is_ascii_digit() || c == '-' => {
+                let len = 1 + chars[i + 1..]
+                    .iter()
+                    .take_while(|d| d.is_ascii_digit() || **d == '.')
+                    .count();
+                let num: String = chars[i..i + len].iter().collect();
+                tokens
+                    .push(Token::Num(num.parse().map_err(|_| {
+                        format!("invalid number {} in rule: {}", num, raw)
+                    })?));
+                i += len;
+            }
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                let len = ident_len(&chars[i..]);
+                tokens.push(Token::Ident(chars[i..i + len].iter().collect()));
+                i += len;
+            }
+            _ => return Err(format!("unexpected {:?} in rule: {}", c, raw)),
+        }
+    }
+    Ok(tokens)
+}
+
+fn ident_len(chars: &[char]) -> usize {
+    chars
+        .iter()
+        .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+        .count()
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn next(&mut self) -> Option<Token> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+    fn eat(&mut self, tok: &Token) -> bool {
+        if self.peek() == Some(tok) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.parse_and()?;
+        while self.eat(&Token::Or) {
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.parse_unary()?;
+        while self.eat(&Token::And) {
+            lhs = Expr::And(Box::new(lhs), Box::new(self.parse_unary()?));
+        }
+        Ok(lhs)
+    }
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        let lhs = self.parse_operand()?;
+        if self.eat(&Token::Eq) {
+            Ok(Expr::Eq(Box::new(lhs), Box::new(self.parse_operand()?)))
+        } else if self.eat(&Token::Ne) {
+            Ok(Expr::Ne(Box::new(lhs), Box::new(self.parse_operand()?)))
+        } else {
+            Ok(lhs)
+        }
+    }
+    fn parse_operand(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                if !self.eat(&Token::RParen) {
+                    return Err("expected ')'".to_owned());
+                }
+                Ok(expr)
+            }
+            Some(Token::Param(name)) => Ok(Expr::Param(name)),
+            Some(Token::Str(s)) => Ok(Expr::Literal(Value::String(s))),
+            Some(Token::Num(n)) => Ok(Expr::Literal(n.into())),
+            Some(Token::Ident(ident)) => match ident.as_str() {
+                "anyone" => Ok(Expr::Anyone),
+                "authenticated" => Ok(Expr::Authenticated),
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                "auth" => {
+                    let mut path = Vec::new();
+                    while self.eat(&Token::Dot) {
+                        match self.next() {
+                            Some(Token::Ident(field)) => path.push(field),
+                            other => return Err(format!("expected a claim name, got {:?}", other)),
+                        }
+                    }
+                    if path.is_empty() {
+                        return Err("expected a claim like `auth.uid`".to_owned());
+                    }
+                    Ok(Expr::Claim(path))
+                }
+                _ => Err(format!("unknown identifier: {}", ident)),
+            },
+            other => Err(format!("expected an operand, got {:?}", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Caller, Rule};
+    use serde_json::{json, Map, Value};
+    use std::collections::BTreeMap;
+
+    fn params(v: Value) -> BTreeMap<String, Value> {
+        serde_json::from_value(v).unwrap()
+    }
+    fn user(v: Value) -> Caller {
+        let claims: Map<String, Value> = serde_json::from_value(v).unwrap();
+        Caller::authenticated(claims)
+    }
+
+    #[test]
+    fn simple_rules() {
+        let anyone: Rule = "anyone".parse().unwrap();
+        let authenticated: Rule = "authenticated".parse().unwrap();
+        let nobody = params(json!({}));
+        assert!(anyone.allows(&Caller::anonymous(), &nobody));
+        assert!(!authenticated.allows(&Caller::anonymous(), &nobody));
+        assert!(authenticated.allows(&user(json!({"uid": "alice"})), &nobody));
+    }
+
+    #[test]
+    fn expressions_over_claims_and_params() {
+        let rule: Rule = "auth.uid == :owner || auth.role == 'admin'"
+            .parse()
+            .unwrap();
+        let alice = user(json!({"uid": "alice"}));
+        let admin = user(json!({"uid": "bob", "role": "admin"}));
+        assert!(rule.allows(&alice, &params(json!({":owner": "alice"}))));
+        assert!(!rule.allows(&alice, &params(json!({":owner": "bob"}))));
+        assert!(rule.allows(&admin, &params(json!({":owner": "alice"}))));
+        assert!(!rule.allows(&Caller::anonymous(), &params(json!({}))));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!("".parse::<Rule>().is_err());
+        assert!("everyone".parse::<Rule>().is_err());
+        assert!("auth.uid ==".parse::<Rule>().is_err());
+        assert!("(anyone".parse::<Rule>().is_err());
+        assert!("'unterminated".parse::<Rule>().is_err());
+    }
+}
@@ -1,19 +1,39 @@
 use actix::Addr;
-use actix_web::dev::{HttpServiceFactory, ServiceRequest};
-use actix_web::{web, Error, HttpResponse};
+use actix_web::dev::{HttpResponseBuilder, HttpServiceFactory, ServiceRequest};
+use actix_web::error::InternalError;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
 
-use crate::core::{DataMessage, EzdbMessage, Policy, RoutingActor};
-use crate::persistence::{PersistenceError, PersistenceResult};
+use crate::analyzer::is_auth_param;
+use crate::core::{
+    BatchStep, CoreActor, DataMessage, EzdbMessage, GetRoutingMetrics, LogisticsMessage, Migration,
+    MutationPolicy, PageRequest, Policy, PolicyChange, QueryPolicy, RoutingActor, RowChunks,
+    RowSink,
+};
+use crate::credentials::{
+    AdminIdentity, AuthenticateEndUser, CredentialActor, DeleteJwtConfig, GetJwtConfig,
+    IssueAdminKey, JwtConfig, ListAdminKeys, RevokeAdminKey, SetJwtConfig, VerifyAdminKey,
+};
+use crate::persistence::{PersistenceError, PersistenceResult, RowFormat};
+use crate::rules::{Caller, Rule};
 use crate::tokens::{DatabaseAddress, DatabaseId, ProjectId};
+use crate::values::{self, ValueEncoding};
+use actix::{Handler, Message};
+use actix_web::web::Bytes;
+use actix_web::HttpMessage;
 use actix_web_httpauth::extractors::bearer::{BearerAuth, Config};
 use actix_web_httpauth::extractors::AuthenticationError;
 use actix_web_httpauth::middleware::HttpAuthentication;
+use futures::future::{self, Either, FutureExt};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::BTreeMap;
 
 pub fn rest_service() -> impl HttpServiceFactory {
     let auth = HttpAuthentication::bearer(verify_admin_auth);
     web::scope("/v0/{project_id}/{database_id}")
+        .app_data(json_config())
         .service(
             web::resource("/raw")
                 .wrap(auth.clone())
@@ -22,10 +42,64 @@ pub fn rest_service() -> impl HttpServiceFactory {
         )
         .service(
             web::resource("/policy")
-                .wrap(auth)
+                .wrap(auth.clone())
                 .route(web::get().to(handle_policy_get))
                 .route(web::put().to(handle_policy_put)),
         )
+        .service(
+            web::resource("/policy/history")
+                .wrap(auth.clone())
+                .route(web::get().to(handle_policy_history_get)),
+        )
+        .service(
+            web::resource("/policy/revisions/{revision}")
+                .wrap(auth.clone())
+                .route(web::get().to(handle_policy_revision_get)),
+        )
+        .service(
+            web::resource("/policy/revisions/{revision}/rollback")
+                .wrap(auth.clone())
+                .route(web::post().to(handle_policy_rollback_post)),
+        )
+        .service(
+            web::resource("/policy/queries/{name}")
+                .wrap(auth.clone())
+                .route(web::put().to(handle_policy_query_put))
+                .route(web::delete().to(handle_policy_query_delete)),
+        )
+        .service(
+            web::resource("/policy/mutations/{name}")
+                .wrap(auth.clone())
+                .route(web::put().to(handle_policy_mutation_put))
+                .route(web::delete().to(handle_policy_mutation_delete)),
+        )
+        .service(
+            web::resource("/schema")
+                .wrap(auth.clone())
+                .route(web::get().to(handle_schema_get)),
+        )
+        .service(
+            web::resource("/migrations")
+                .wrap(auth.clone())
+                .route(web::get().to(handle_migrations_get))
+                .route(web::post().to(handle_migrations_post)),
+        )
+        .service(
+            web::resource("/interrupt")
+                .wrap(auth.clone())
+                .route(web::post().to(handle_interrupt_post)),
+        )
+        .service(
+            web::resource("/jobs")
+                .wrap(auth.clone())
+                .route(web::get().to(handle_jobs_get)),
+        )
+        .service(
+            web::resource("/jobs/{job_id}")
+                .wrap(auth)
+                .route(web::delete().to(handle_job_delete)),
+        )
+        .service(web::resource("/batch").route(web::post().to(handle_batch_post)))
         .service(
             web::resource("/named/{name}")
                 .route(web::get().to(handle_named_get))
@@ -33,38 +107,280 @@ pub fn rest_service() -> impl HttpServiceFactory {
         )
 }
 
+/// Endpoints for the server operator: managing each project's admin keys, and
+/// monitoring the server.
+pub fn admin_service() -> impl HttpServiceFactory {
+    let root_auth = HttpAuthentication::bearer(verify_root_auth);
+    web::scope("/admin/v0")
+        .app_data(json_config())
+        .service(
+            web::resource("/metrics")
+                .wrap(root_auth.clone())
+                .route(web::get().to(handle_metrics_get)),
+        )
+        .service(
+            web::scope("/{project_id}")
+                .service(
+                    web::resource("/keys")
+                        .wrap(root_auth.clone())
+                        .route(web::get().to(handle_keys_get))
+                        .route(web::post().to(handle_keys_post)),
+                )
+                .service(
+                    web::resource("/keys/{key_id}")
+                        .wrap(root_auth)
+                        .route(web::delete().to(handle_key_delete)),
+                )
+                .service(
+                    web::resource("/jwt")
+                        .wrap(HttpAuthentication::bearer(verify_admin_auth))
+                        .route(web::get().to(handle_jwt_get))
+                        .route(web::put().to(handle_jwt_put))
+                        .route(web::delete().to(handle_jwt_delete)),
+                ),
+        )
+}
+
+// Reports malformed request bodies in the same shape as every other error.
+fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err, _req| {
+        let response = wrap_output(Err(PersistenceError::InvalidArgument(err.to_string())));
+        InternalError::from_response(err, response).into()
+    })
+}
+
 async fn verify_admin_auth(
     req: ServiceRequest,
     credentials: BearerAuth,
 ) -> Result<ServiceRequest, Error> {
-    if credentials.token() != "admin" {
-        return Err(AuthenticationError::from(Config::default()).into());
+    let project_id = req
+        .match_info()
+        .get("project_id")
+        .and_then(|raw| raw.parse().ok());
+    match verify_credentials(&req, project_id, credentials.token()).await? {
+        Some(identity) => {
+            req.extensions_mut().insert(identity);
+            Ok(req)
+        }
+        None => Err(AuthenticationError::from(Config::default()).into()),
     }
-    Ok(req)
+}
+
+async fn verify_root_auth(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, Error> {
+    match verify_credentials(&req, None, credentials.token()).await? {
+        Some(AdminIdentity::Root) => Ok(req),
+        _ => Err(AuthenticationError::from(Config::default()).into()),
+    }
+}
+
+async fn verify_credentials(
+    req: &ServiceRequest,
+    project_id: Option<ProjectId>,
+    token: &str,
+) -> Result<Option<AdminIdentity>, Error> {
+    let credentials = req
+        .app_data::<web::Data<Addr<CredentialActor>>>()
+        .cloned()
+        .ok_or_else(|| actix_web::error::ErrorInternalServerError("no credential store"))?;
+    let msg = VerifyAdminKey {
+        project_id,
+        token: token.to_owned(),
+    };
+    credentials
+        .send(msg)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("{:?}", e)))
+}
+
+async fn handle_metrics_get(srv: web::Data<Addr<RoutingActor>>) -> Result<HttpResponse, Error> {
+    let metrics = srv
+        .send(GetRoutingMetrics)
+        .await
+        .map_err(PersistenceError::from);
+    Ok(wrap_output(
+        metrics.map(|m| serde_json::to_string(&m).expect("serialize")),
+    ))
+}
+
+async fn handle_keys_get(
+    path: web::Path<ProjectId>,
+    credentials: web::Data<Addr<CredentialActor>>,
+) -> Result<HttpResponse, Error> {
+    let msg = ListAdminKeys(path.into_inner());
+    Ok(wrap_output(
+        handle_credentials(credentials.get_ref(), msg).await,
+    ))
+}
+
+async fn handle_keys_post(
+    path: web::Path<ProjectId>,
+    credentials: web::Data<Addr<CredentialActor>>,
+) -> Result<HttpResponse, Error> {
+    let msg = IssueAdminKey(path.into_inner());
+    Ok(wrap_output(
+        handle_credentials(credentials.get_ref(), msg).await,
+    ))
+}
+
+async fn handle_key_delete(
+    path: web::Path<(ProjectId, String)>,
+    credentials: web::Data<Addr<CredentialActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, key_id) = path.into_inner();
+    let msg = RevokeAdminKey(project_id, key_id);
+    Ok(wrap_output(
+        handle_credentials(credentials.get_ref(), msg).await,
+    ))
+}
+
+async fn handle_jwt_get(
+    path: web::Path<ProjectId>,
+    credentials: web::Data<Addr<CredentialActor>>,
+) -> Result<HttpResponse, Error> {
+    let msg = GetJwtConfig(path.into_inner());
+    Ok(wrap_output(
+        handle_credentials(credentials.get_ref(), msg).await,
+    ))
+}
+
+async fn handle_jwt_put(
+    path: web::Path<ProjectId>,
+    config: web::Json<JwtConfig>,
+    credentials: web::Data<Addr<CredentialActor>>,
+) -> Result<HttpResponse, Error> {
+    let msg = SetJwtConfig(path.into_inner(), config.into_inner());
+    Ok(wrap_output(
+        handle_credentials(credentials.get_ref(), msg).await,
+    ))
+}
+
+async fn handle_jwt_delete(
+    path: web::Path<ProjectId>,
+    credentials: web::Data<Addr<CredentialActor>>,
+) -> Result<HttpResponse, Error> {
+    let msg = DeleteJwtConfig(path.into_inner());
+    Ok(wrap_output(
+        handle_credentials(credentials.get_ref(), msg).await,
+    ))
 }
 
 async fn handle_raw_get(
+    req: HttpRequest,
     path: web::Path<(ProjectId, DatabaseId)>,
+    options: web::Query<StreamOptions>,
     query: String,
     srv: web::Data<Addr<RoutingActor>>,
 ) -> Result<HttpResponse, Error> {
     let (project_id, database_id) = path.into_inner();
+    let db_addr = DatabaseAddress {
+        project_id,
+        database_id,
+    };
+    let encoding = match value_encoding(&req) {
+        Ok(encoding) => encoding,
+        Err(e) => return Ok(wrap_output(Err(e))),
+    };
+    let format = match stream_format(&req, &options, encoding) {
+        Ok(format) => format,
+        Err(e) => return Ok(wrap_output(Err(e))),
+    };
+    if let Some(format) = format {
+        let (sink, chunks) = RowSink::new(format, encoding);
+        return Ok(
+            match srv.send(db_addr).await.map_err(PersistenceError::from) {
+                Ok(Ok(core)) => {
+                    stream_output(core, DataMessage::StreamRaw(query, sink), chunks).await
+                }
+                Ok(Err(e)) | Err(e) => wrap_output(Err(e)),
+            },
+        );
+    }
     Ok(wrap_output(
+        handle_message(
+            srv.get_ref(),
+            db_addr,
+            EzdbMessage::Data(DataMessage::QueryRaw(query, encoding)),
+        )
+        .await,
+    ))
+}
+
+async fn handle_raw_post(
+    req: HttpRequest,
+    path: web::Path<(ProjectId, DatabaseId)>,
+    stmt: String,
+    srv: web::Data<Addr<RoutingActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id) = path.into_inner();
+    let encoding = match value_encoding(&req) {
+        Ok(encoding) => encoding,
+        Err(e) => return Ok(wrap_output(Err(e))),
+    };
+    let db_addr = DatabaseAddress {
+        project_id,
+        database_id,
+    };
+    match negotiate_format(&req, encoding) {
+        Ok(RowFormat::Json) => {}
+        Ok(format) => {
+            let (sink, chunks) = RowSink::new(format, encoding);
+            return Ok(
+                match srv.send(db_addr).await.map_err(PersistenceError::from) {
+                    Ok(Ok(core)) => {
+                        let msg = DataMessage::StreamMutationRaw(stmt, sink);
+                        buffered_output(core, msg, chunks).await
+                    }
+                    Ok(Err(e)) | Err(e) => wrap_output(Err(e)),
+                },
+            );
+        }
+        Err(e) => return Ok(wrap_output(Err(e))),
+    }
+    Ok(wrap_output(
+        handle_message(
+            srv.get_ref(),
+            db_addr,
+            EzdbMessage::Data(DataMessage::MutateRaw(stmt, encoding)),
+        )
+        .await,
+    ))
+}
+
+async fn handle_policy_get(
+    path: web::Path<(ProjectId, DatabaseId)>,
+    srv: web::Data<Addr<RoutingActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id) = path.into_inner();
+    Ok(wrap_policy_output(
         handle_message(
             srv.get_ref(),
             DatabaseAddress {
                 project_id,
                 database_id,
             },
-            EzdbMessage::Data(DataMessage::QueryRaw(query)),
+            EzdbMessage::Data(DataMessage::FetchPolicy),
         )
         .await,
     ))
 }
 
-async fn handle_raw_post(
+async fn handle_policy_put(
+    req: HttpRequest,
+    path: web::Path<(ProjectId, DatabaseId)>,
+    policy: web::Json<Policy>,
+    srv: web::Data<Addr<RoutingActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id) = path.into_inner();
+    let change = PolicyChange::Replace(policy.into_inner());
+    Ok(update_policy(&req, srv.get_ref(), project_id, database_id, change).await)
+}
+
+async fn handle_policy_history_get(
     path: web::Path<(ProjectId, DatabaseId)>,
-    stmt: String,
     srv: web::Data<Addr<RoutingActor>>,
 ) -> Result<HttpResponse, Error> {
     let (project_id, database_id) = path.into_inner();
@@ -75,13 +391,181 @@ async fn handle_raw_post(
                 project_id,
                 database_id,
             },
-            EzdbMessage::Data(DataMessage::MutateRaw(stmt)),
+            EzdbMessage::Data(DataMessage::FetchPolicyHistory),
         )
         .await,
     ))
 }
 
-async fn handle_policy_get(
+async fn handle_policy_revision_get(
+    path: web::Path<(ProjectId, DatabaseId, u64)>,
+    srv: web::Data<Addr<RoutingActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id, revision) = path.into_inner();
+    Ok(wrap_output(
+        handle_message(
+            srv.get_ref(),
+            DatabaseAddress {
+                project_id,
+                database_id,
+            },
+            EzdbMessage::Data(DataMessage::FetchPolicyRevision(revision)),
+        )
+        .await,
+    ))
+}
+
+async fn handle_policy_rollback_post(
+    req: HttpRequest,
+    path: web::Path<(ProjectId, DatabaseId, u64)>,
+    srv: web::Data<Addr<RoutingActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id, revision) = path.into_inner();
+    let change = PolicyChange::Rollback(revision);
+    Ok(update_policy(&req, srv.get_ref(), project_id, database_id, change).await)
+}
+
+async fn handle_policy_query_put(
+    req: HttpRequest,
+    path: web::Path<(ProjectId, DatabaseId, String)>,
+    template: web::Json<TemplateBody>,
+    srv: web::Data<Addr<RoutingActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id, name) = path.into_inner();
+    let TemplateBody {
+        raw_sql,
+        rule,
+        timeout_ms,
+    } = template.into_inner();
+    let change = PolicyChange::PutQuery(QueryPolicy {
+        name,
+        raw_sql,
+        rule,
+        timeout_ms,
+    });
+    Ok(update_policy(&req, srv.get_ref(), project_id, database_id, change).await)
+}
+
+async fn handle_policy_query_delete(
+    req: HttpRequest,
+    path: web::Path<(ProjectId, DatabaseId, String)>,
+    srv: web::Data<Addr<RoutingActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id, name) = path.into_inner();
+    let change = PolicyChange::DeleteQuery(name);
+    Ok(update_policy(&req, srv.get_ref(), project_id, database_id, change).await)
+}
+
+async fn handle_policy_mutation_put(
+    req: HttpRequest,
+    path: web::Path<(ProjectId, DatabaseId, String)>,
+    template: web::Json<TemplateBody>,
+    srv: web::Data<Addr<RoutingActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id, name) = path.into_inner();
+    let TemplateBody {
+        raw_sql,
+        rule,
+        timeout_ms,
+    } = template.into_inner();
+    let change = PolicyChange::PutMutation(MutationPolicy {
+        name,
+        raw_sql,
+        rule,
+        timeout_ms,
+    });
+    Ok(update_policy(&req, srv.get_ref(), project_id, database_id, change).await)
+}
+
+async fn handle_policy_mutation_delete(
+    req: HttpRequest,
+    path: web::Path<(ProjectId, DatabaseId, String)>,
+    srv: web::Data<Addr<RoutingActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id, name) = path.into_inner();
+    let change = PolicyChange::DeleteMutation(name);
+    Ok(update_policy(&req, srv.get_ref(), project_id, database_id, change).await)
+}
+
+/// A query or mutation in a policy, named by its path instead of its body.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TemplateBody {
+    raw_sql: String,
+    #[serde(default)]
+    rule: Rule,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+// Makes a change to the policy, only if it's still at the version in the request's
+// `If-Match` header, if it has one.
+async fn update_policy(
+    req: &HttpRequest,
+    router: &Addr<RoutingActor>,
+    project_id: ProjectId,
+    database_id: DatabaseId,
+    change: PolicyChange,
+) -> HttpResponse {
+    let if_version = match if_match_version(req) {
+        Ok(if_version) => if_version,
+        Err(e) => return wrap_output(Err(e)),
+    };
+    // `verify_admin_auth` leaves the identity it verified on the request.
+    let author = req
+        .extensions()
+        .get::<AdminIdentity>()
+        .map(|identity| identity.to_string());
+    wrap_policy_output(
+        handle_message(
+            router,
+            DatabaseAddress {
+                project_id,
+                database_id,
+            },
+            EzdbMessage::Data(DataMessage::UpdatePolicy(change, if_version, author)),
+        )
+        .await,
+    )
+}
+
+// A policy's ETag is its version, quoted. `*` matches any version.
+fn if_match_version(req: &HttpRequest) -> PersistenceResult<Option<u64>> {
+    let header = match req.headers().get(header::IF_MATCH) {
+        None => return Ok(None),
+        Some(header) => header.to_str().unwrap_or_default().trim(),
+    };
+    if header == "*" {
+        return Ok(None);
+    }
+    header
+        .strip_prefix('"')
+        .and_then(|h| h.strip_suffix('"'))
+        .and_then(|version| version.parse().ok())
+        .map(Some)
+        .ok_or_else(|| {
+            PersistenceError::InvalidArgument("If-Match must be a policy's ETag".to_owned())
+        })
+}
+
+// Like `wrap_output`, but tags responses that carry a policy's `version` with an ETag.
+fn wrap_policy_output(result: PersistenceResult<String>) -> HttpResponse {
+    let version = result
+        .as_ref()
+        .ok()
+        .and_then(|data| serde_json::from_str::<Value>(data).ok())
+        .and_then(|data| data["version"].as_u64());
+    let mut response = wrap_output(result);
+    if let Some(version) = version {
+        response.headers_mut().insert(
+            header::ETAG,
+            header::HeaderValue::from_str(&format!("\"{}\"", version)).expect("header"),
+        );
+    }
+    response
+}
+
+async fn handle_schema_get(
     path: web::Path<(ProjectId, DatabaseId)>,
     srv: web::Data<Addr<RoutingActor>>,
 ) -> Result<HttpResponse, Error> {
@@ -93,15 +577,14 @@ async fn handle_policy_get(
                 project_id,
                 database_id,
             },
-            EzdbMessage::Data(DataMessage::FetchPolicy),
+            EzdbMessage::Data(DataMessage::FetchSchema),
         )
         .await,
     ))
 }
 
-async fn handle_policy_put(
+async fn handle_migrations_get(
     path: web::Path<(ProjectId, DatabaseId)>,
-    policy: web::Json<Policy>,
     srv: web::Data<Addr<RoutingActor>>,
 ) -> Result<HttpResponse, Error> {
     let (project_id, database_id) = path.into_inner();
@@ -112,18 +595,19 @@ async fn handle_policy_put(
                 project_id,
                 database_id,
             },
-            EzdbMessage::Data(DataMessage::SetPolicy(policy.into_inner())),
+            EzdbMessage::Data(DataMessage::ListMigrations),
         )
         .await,
     ))
 }
 
-async fn handle_named_get(
-    path: web::Path<(ProjectId, DatabaseId, String)>,
+async fn handle_migrations_post(
+    path: web::Path<(ProjectId, DatabaseId)>,
+    options: web::Query<MigrationOptions>,
+    migrations: web::Json<Vec<Migration>>,
     srv: web::Data<Addr<RoutingActor>>,
-    params: web::Json<BTreeMap<String, Value>>,
 ) -> Result<HttpResponse, Error> {
-    let (project_id, database_id, name) = path.into_inner();
+    let (project_id, database_id) = path.into_inner();
     Ok(wrap_output(
         handle_message(
             srv.get_ref(),
@@ -131,26 +615,217 @@ async fn handle_named_get(
                 project_id,
                 database_id,
             },
-            EzdbMessage::Data(DataMessage::QueryNamed(name, params.into_inner())),
+            EzdbMessage::Data(DataMessage::ApplyMigrations(
+                migrations.into_inner(),
+                options.dry_run,
+            )),
         )
         .await,
     ))
 }
 
+/// `?dryRun=true` checks the pending migrations without applying them.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MigrationOptions {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+async fn handle_interrupt_post(
+    path: web::Path<(ProjectId, DatabaseId)>,
+    srv: web::Data<Addr<RoutingActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id) = path.into_inner();
+    Ok(wrap_output(
+        handle_message(
+            srv.get_ref(),
+            DatabaseAddress {
+                project_id,
+                database_id,
+            },
+            EzdbMessage::Logistics(LogisticsMessage::Interrupt),
+        )
+        .await
+        .map(|_| serde_json::to_string(&()).expect("serialize")),
+    ))
+}
+
+async fn handle_jobs_get(
+    path: web::Path<(ProjectId, DatabaseId)>,
+    srv: web::Data<Addr<RoutingActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id) = path.into_inner();
+    Ok(wrap_output(
+        handle_message(
+            srv.get_ref(),
+            DatabaseAddress {
+                project_id,
+                database_id,
+            },
+            EzdbMessage::Logistics(LogisticsMessage::ListJobs),
+        )
+        .await,
+    ))
+}
+
+async fn handle_job_delete(
+    path: web::Path<(ProjectId, DatabaseId, u64)>,
+    srv: web::Data<Addr<RoutingActor>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id, job_id) = path.into_inner();
+    Ok(wrap_output(
+        handle_message(
+            srv.get_ref(),
+            DatabaseAddress {
+                project_id,
+                database_id,
+            },
+            EzdbMessage::Logistics(LogisticsMessage::Cancel(job_id)),
+        )
+        .await
+        .map(|_| serde_json::to_string(&()).expect("serialize")),
+    ))
+}
+
+async fn handle_named_get(
+    req: HttpRequest,
+    path: web::Path<(ProjectId, DatabaseId, String)>,
+    page: web::Query<PageRequest>,
+    options: web::Query<StreamOptions>,
+    srv: web::Data<Addr<RoutingActor>>,
+    credentials: web::Data<Addr<CredentialActor>>,
+    params: web::Json<BTreeMap<String, Value>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id, name) = path.into_inner();
+    let page = page.into_inner();
+    let encoding = match value_encoding(&req) {
+        Ok(encoding) => encoding,
+        Err(e) => return Ok(wrap_output(Err(e))),
+    };
+    // Paged results need a `nextPageToken` to go with them. JSON pages come wrapped with
+    // it, and pages in other formats are buffered so it can go in a header.
+    let paged = page.limit.is_some() || page.page_token.is_some();
+    let format = match negotiate_format(&req, encoding) {
+        Ok(format) => format,
+        Err(e) => return Ok(wrap_output(Err(e))),
+    };
+    let (sink, chunks) = match format {
+        RowFormat::Json if paged || !options.stream => (None, None),
+        format => {
+            let (sink, chunks) = RowSink::new(format, encoding);
+            (Some(sink), Some(chunks))
+        }
+    };
+    let prepared = prepare_named_message(
+        srv.get_ref(),
+        credentials.get_ref(),
+        &req,
+        DatabaseAddress {
+            project_id,
+            database_id,
+        },
+        |caller| {
+            let params = decode_params(encoding, params.into_inner());
+            let params = bind_caller_params(&caller, params)?;
+            Ok(match sink {
+                Some(sink) if paged => {
+                    DataMessage::StreamNamed(caller, name, params, Some(page), sink)
+                }
+                Some(sink) => DataMessage::StreamNamed(caller, name, params, None, sink),
+                None if paged => DataMessage::QueryPage(caller, name, params, page, encoding),
+                None => DataMessage::QueryNamed(caller, name, params, encoding),
+            })
+        },
+    )
+    .await;
+    Ok(match (prepared, chunks) {
+        (Err(e), _) => wrap_output(Err(e)),
+        (Ok((core, msg)), Some(chunks)) if paged => buffered_output(core, msg, chunks).await,
+        (Ok((core, msg)), Some(chunks)) => stream_output(core, msg, chunks).await,
+        (Ok((core, msg)), None) => wrap_output(send_data_message(&core, msg).await),
+    })
+}
+
 async fn handle_named_post(
+    req: HttpRequest,
     path: web::Path<(ProjectId, DatabaseId, String)>,
     srv: web::Data<Addr<RoutingActor>>,
+    credentials: web::Data<Addr<CredentialActor>>,
     params: web::Json<BTreeMap<String, Value>>,
 ) -> Result<HttpResponse, Error> {
     let (project_id, database_id, name) = path.into_inner();
+    let encoding = match value_encoding(&req) {
+        Ok(encoding) => encoding,
+        Err(e) => return Ok(wrap_output(Err(e))),
+    };
+    // Rows in formats other than JSON can't hold the counts, so those go in headers.
+    let (sink, chunks) = match negotiate_format(&req, encoding) {
+        Ok(RowFormat::Json) => (None, None),
+        Ok(format) => {
+            let (sink, chunks) = RowSink::new(format, encoding);
+            (Some(sink), Some(chunks))
+        }
+        Err(e) => return Ok(wrap_output(Err(e))),
+    };
+    let prepared = prepare_named_message(
+        srv.get_ref(),
+        credentials.get_ref(),
+        &req,
+        DatabaseAddress {
+            project_id,
+            database_id,
+        },
+        |caller| {
+            let params = decode_params(encoding, params.into_inner());
+            let params = bind_caller_params(&caller, params)?;
+            Ok(match sink {
+                Some(sink) => DataMessage::StreamMutationNamed(caller, name, params, sink),
+                None => DataMessage::MutateNamed(caller, name, params, encoding),
+            })
+        },
+    )
+    .await;
+    Ok(match (prepared, chunks) {
+        (Err(e), _) => wrap_output(Err(e)),
+        (Ok((core, msg)), Some(chunks)) => buffered_output(core, msg, chunks).await,
+        (Ok((core, msg)), None) => wrap_output(send_data_message(&core, msg).await),
+    })
+}
+
+async fn handle_batch_post(
+    req: HttpRequest,
+    path: web::Path<(ProjectId, DatabaseId)>,
+    srv: web::Data<Addr<RoutingActor>>,
+    credentials: web::Data<Addr<CredentialActor>>,
+    steps: web::Json<Vec<BatchStep>>,
+) -> Result<HttpResponse, Error> {
+    let (project_id, database_id) = path.into_inner();
+    let encoding = match value_encoding(&req) {
+        Ok(encoding) => encoding,
+        Err(e) => return Ok(wrap_output(Err(e))),
+    };
     Ok(wrap_output(
-        handle_message(
+        handle_named_message(
             srv.get_ref(),
+            credentials.get_ref(),
+            &req,
             DatabaseAddress {
                 project_id,
                 database_id,
             },
-            EzdbMessage::Data(DataMessage::MutateNamed(name, params.into_inner())),
+            |caller| {
+                let steps = steps
+                    .into_inner()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, step)| {
+                        bind_step_params(&caller, decode_step_params(encoding, step))
+                            .map_err(|e| e.in_step(i))
+                    })
+                    .collect::<PersistenceResult<_>>()?;
+                Ok(DataMessage::Batch(caller, steps, encoding))
+            },
         )
         .await,
     ))
@@ -165,32 +840,479 @@ async fn handle_message(
     core.send(msg).await?
 }
 
+async fn handle_credentials<M, T>(
+    credentials: &Addr<CredentialActor>,
+    msg: M,
+) -> PersistenceResult<String>
+where
+    M: Message<Result = PersistenceResult<T>> + Send + 'static,
+    T: Serialize + Send + 'static,
+    CredentialActor: Handler<M>,
+{
+    let data = credentials.send(msg).await??;
+    Ok(serde_json::to_string(&data).expect("serialize"))
+}
+
+/// Like `handle_message`, but for end users: it authenticates the caller and builds the
+/// message for them, binding their claims as `:auth.*` params. The database checks the
+/// message against the rules of the templates it runs as it runs them.
+async fn handle_named_message(
+    router: &Addr<RoutingActor>,
+    credentials: &Addr<CredentialActor>,
+    req: &HttpRequest,
+    db_addr: DatabaseAddress,
+    to_message: impl FnOnce(Caller) -> PersistenceResult<DataMessage>,
+) -> PersistenceResult<String> {
+    let (core, msg) = prepare_named_message(router, credentials, req, db_addr, to_message).await?;
+    send_data_message(&core, msg).await
+}
+
+async fn send_data_message(core: &Addr<CoreActor>, msg: DataMessage) -> PersistenceResult<String> {
+    core.send(EzdbMessage::Data(msg)).await?
+}
+
+/// Does everything `handle_named_message` does but send the message, returning it and
+/// the database to send it to instead.
+async fn prepare_named_message(
+    router: &Addr<RoutingActor>,
+    credentials: &Addr<CredentialActor>,
+    req: &HttpRequest,
+    db_addr: DatabaseAddress,
+    to_message: impl FnOnce(Caller) -> PersistenceResult<DataMessage>,
+) -> PersistenceResult<(Addr<CoreActor>, DataMessage)> {
+    let caller = authenticate_caller(credentials, req, &db_addr.project_id).await?;
+    let msg = to_message(caller)?;
+    let core = router.send(db_addr).await??;
+    Ok((core, msg))
+}
+
+/// Whether to stream a query's rows: `?stream=true` streams the usual JSON array.
+/// Rows in any other format than that are always streamed, unless they're a page.
+#[derive(Debug, Deserialize)]
+struct StreamOptions {
+    #[serde(default)]
+    stream: bool,
+}
+
+fn stream_format(
+    req: &HttpRequest,
+    options: &StreamOptions,
+    encoding: ValueEncoding,
+) -> PersistenceResult<Option<RowFormat>> {
+    Ok(match negotiate_format(req, encoding)? {
+        RowFormat::Json if !options.stream => None,
+        format => Some(format),
+    })
+}
+
+// Picks the row format the client prefers, going by the `q` weights in its `Accept`
+// header, out of the ones that can write values in `encoding`. Clients that don't say
+// get JSON, and ones that don't accept any of the formats get `NotAcceptable`.
+fn negotiate_format(req: &HttpRequest, encoding: ValueEncoding) -> PersistenceResult<RowFormat> {
+    let accept = match req.headers().get(header::ACCEPT) {
+        Some(accept) => accept.to_str().unwrap_or_default(),
+        None => return Ok(RowFormat::Json),
+    };
+    if accept.trim().is_empty() {
+        return Ok(RowFormat::Json);
+    }
+    let mut best: Option<(f32, RowFormat)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or_default();
+        let format = match media_type {
+            "*/*" | "application/*" => Some(RowFormat::Json),
+            "text/*" => Some(RowFormat::Csv),
+            _ => RowFormat::ALL
+                .iter()
+                .copied()
+                .find(|format| format.content_type().eq_ignore_ascii_case(media_type)),
+        };
+        let quality = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        if let Some(format) = format.filter(|format| format.can_encode(encoding)) {
+            if quality > 0.0 && best.is_none_or(|(q, _)| quality > q) {
+                best = Some((quality, format));
+            }
+        }
+    }
+    best.map(|(_, format)| format)
+        .ok_or_else(|| PersistenceError::NotAcceptable {
+            accept: accept.to_owned(),
+            encoding,
+        })
+}
+
+// The content type of the rows a message writes to its sink.
+fn sink_content_type(msg: &DataMessage) -> &'static str {
+    match msg {
+        DataMessage::StreamNamed(_, _, _, _, sink)
+        | DataMessage::StreamMutationNamed(_, _, _, sink)
+        | DataMessage::StreamRaw(_, sink)
+        | DataMessage::StreamMutationRaw(_, sink) => sink.format.content_type(),
+        _ => "application/json",
+    }
+}
+
+// The fields of a streamed message's result that go in response headers, since the body
+// is all rows.
+const RESULT_HEADERS: &[(&str, &str)] = &[
+    ("nextPageToken", "Ezdb-Next-Page-Token"),
+    ("changes", "Ezdb-Changes"),
+    ("lastInsertRowid", "Ezdb-Last-Insert-Rowid"),
+];
+
+// Sends a message that writes rows to `chunks`, and responds with all of them once it's
+// done, putting the fields of its result in `RESULT_HEADERS`. That's for results that
+// need more than rows to make sense, like a page and the token for the next one, or
+// what a mutation changed. They're no bigger than `--max-rows` allows, so holding them
+// until they're complete is fine.
+async fn buffered_output(
+    core: Addr<CoreActor>,
+    msg: DataMessage,
+    chunks: RowChunks,
+) -> HttpResponse {
+    let content_type = sink_content_type(&msg);
+    let (result, body) = future::join(send_data_message(&core, msg), chunks.concat()).await;
+    let result = result.and_then(|result| {
+        serde_json::from_str::<Value>(&result)
+            .map_err(|e| PersistenceError::Unknown(format!("{:?}", e)))
+    });
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => return wrap_output(Err(e)),
+    };
+    let mut response = HttpResponse::Ok();
+    response.content_type(content_type);
+    for (field, name) in RESULT_HEADERS {
+        match result.get(field) {
+            None | Some(Value::Null) => {}
+            Some(Value::String(value)) => {
+                response.set_header(*name, value.as_str());
+            }
+            Some(value) => {
+                response.set_header(*name, value.to_string());
+            }
+        }
+    }
+    response.body(body)
+}
+
+// Sends a message that streams rows to `chunks`, and responds with them as they arrive.
+// Errors before the first chunk get the usual error response, but once the response
+// has started, the only way left to report one is to cut it short.
+async fn stream_output(
+    core: Addr<CoreActor>,
+    msg: DataMessage,
+    mut chunks: RowChunks,
+) -> HttpResponse {
+    let content_type = sink_content_type(&msg);
+    let done = async move { send_data_message(&core, msg).await }.boxed_local();
+    let (first, done) = match future::select(chunks.next(), done).await {
+        Either::Left((Some(first), done)) => (Some(first), done),
+        Either::Left((None, done)) => match done.await {
+            Ok(_) => return HttpResponse::Ok().content_type(content_type).finish(),
+            Err(e) => return wrap_output(Err(e)),
+        },
+        Either::Right((Err(e), _)) => return wrap_output(Err(e)),
+        // Every chunk is already waiting in `chunks`.
+        Either::Right((Ok(_), _)) => (None, future::ready(Ok(String::new())).boxed_local()),
+    };
+    let rows = stream::iter(first)
+        .chain(chunks)
+        .map(|chunk| Ok(Bytes::from(chunk)));
+    let end = stream::once(done).filter_map(|result| {
+        future::ready(result.err().map(|e| {
+            Err(actix_web::error::ErrorInternalServerError(format!(
+                "{:?}",
+                e
+            )))
+        }))
+    });
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .streaming(rows.chain(end))
+}
+
+async fn authenticate_caller(
+    credentials: &Addr<CredentialActor>,
+    req: &HttpRequest,
+    project_id: &ProjectId,
+) -> PersistenceResult<Caller> {
+    let header = match req.headers().get(header::AUTHORIZATION) {
+        None => return Ok(Caller::anonymous()),
+        Some(header) => header,
+    };
+    let token = header
+        .to_str()
+        .ok()
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or_else(|| PersistenceError::Unauthenticated("expected a bearer token".to_owned()))?;
+    credentials
+        .send(AuthenticateEndUser {
+            project_id: project_id.clone(),
+            token: token.to_owned(),
+        })
+        .await?
+}
+
+// The `:auth.*` params come from the caller's verified claims, never from the request body.
+fn bind_caller_params(
+    caller: &Caller,
+    mut params: BTreeMap<String, Value>,
+) -> PersistenceResult<BTreeMap<String, Value>> {
+    if let Some(name) = params.keys().find(|name| is_auth_param(name)) {
+        return Err(PersistenceError::PermissionDenied(format!(
+            "{} is reserved for the caller's claims",
+            name
+        )));
+    }
+    if let Some(claims) = caller.claims() {
+        for (claim, value) in claims {
+            if !value.is_array() && !value.is_object() {
+                params.insert(format!(":auth.{}", claim), value.clone());
+            }
+        }
+    }
+    Ok(params)
+}
+
+/// Reads the `Ezdb-Value-Encoding` header. Requests without one use plain JSON.
+fn value_encoding(req: &HttpRequest) -> PersistenceResult<ValueEncoding> {
+    match req.headers().get(values::HEADER) {
+        None => Ok(ValueEncoding::Plain),
+        Some(header) => header
+            .to_str()
+            .map_err(|e| e.to_string())
+            .and_then(str::parse)
+            .map_err(PersistenceError::InvalidArgument),
+    }
+}
+
+// Persistence binds typed values, so the params of plain requests are converted first.
+fn decode_params(
+    encoding: ValueEncoding,
+    params: BTreeMap<String, Value>,
+) -> BTreeMap<String, Value> {
+    match encoding {
+        ValueEncoding::Plain => values::params_from_plain(params),
+        ValueEncoding::Typed => params,
+    }
+}
+
+fn decode_step_params(encoding: ValueEncoding, step: BatchStep) -> BatchStep {
+    match step {
+        BatchStep::Query { name, params } => BatchStep::Query {
+            name,
+            params: decode_params(encoding, params),
+        },
+        BatchStep::Mutation { name, params } => BatchStep::Mutation {
+            name,
+            params: decode_params(encoding, params),
+        },
+    }
+}
+
+fn bind_step_params(caller: &Caller, step: BatchStep) -> PersistenceResult<BatchStep> {
+    Ok(match step {
+        BatchStep::Query { name, params } => BatchStep::Query {
+            name,
+            params: bind_caller_params(caller, params)?,
+        },
+        BatchStep::Mutation { name, params } => BatchStep::Mutation {
+            name,
+            params: bind_caller_params(caller, params)?,
+        },
+    })
+}
+
 fn wrap_output(result: PersistenceResult<String>) -> HttpResponse {
     match result {
-        Ok(data) => HttpResponse::Ok().body(data),
+        Ok(data) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(data),
         Err(e) => {
-            let payload = match e {
-                PersistenceError::Unknown(msg) => json!({
-                    "code": "unknown",
-                    "message": msg,
-                }),
-                PersistenceError::NoSuchQuery(name) => json!({
-                    "code": "not_found",
-                    "message": "no such query",
-                    "details": {
-                        "name": name,
-                    },
-                }),
-                PersistenceError::Interrupted => json!({
-                    "code": "interrupted",
-                    "message": "Operation was interrupted",
-                }),
-                PersistenceError::Busy => json!({
-                    "code": "busy",
-                    "message": "Database is busy, back off and try again",
-                }),
-            };
-            HttpResponse::BadRequest().body(payload)
+            let mut response = HttpResponse::build(status_code(&e));
+            response.content_type("application/json");
+            let payload = error_payload(e, &mut response);
+            response.body(payload)
+        }
+    }
+}
+
+fn error_payload(e: PersistenceError, response: &mut HttpResponseBuilder) -> Value {
+    match e {
+        PersistenceError::Unknown(msg) => json!({
+            "code": "unknown",
+            "message": msg,
+        }),
+        PersistenceError::NoSuchQuery(name) => json!({
+            "code": "not_found",
+            "message": "no such query",
+            "details": {
+                "name": name,
+            },
+        }),
+        PersistenceError::NoSuchKey(key_id) => json!({
+            "code": "not_found",
+            "message": "no such key",
+            "details": {
+                "keyId": key_id,
+            },
+        }),
+        PersistenceError::NoSuchRevision(revision) => json!({
+            "code": "not_found",
+            "message": "no such policy revision",
+            "details": {
+                "revision": revision,
+            },
+        }),
+        PersistenceError::NoSuchJob(job_id) => json!({
+            "code": "not_found",
+            "message": "no such job",
+            "details": {
+                "jobId": job_id,
+            },
+        }),
+        PersistenceError::PermissionDenied(msg) => json!({
+            "code": "permission_denied",
+            "message": msg,
+        }),
+        PersistenceError::Unauthenticated(msg) => {
+            response.set_header(header::WWW_AUTHENTICATE, "Bearer");
+            json!({
+                "code": "unauthenticated",
+                "message": msg,
+            })
+        }
+        PersistenceError::SyntaxError(msg) => json!({
+            "code": "syntax_error",
+            "message": msg,
+        }),
+        PersistenceError::SchemaError(msg) => json!({
+            "code": "schema_error",
+            "message": msg,
+        }),
+        PersistenceError::ConstraintViolation(msg) => json!({
+            "code": "constraint_violation",
+            "message": msg,
+        }),
+        PersistenceError::InvalidArgument(msg) => json!({
+            "code": "invalid_argument",
+            "message": msg,
+        }),
+        PersistenceError::InvalidParams {
+            missing,
+            unexpected,
+        } => json!({
+            "code": "invalid_argument",
+            "message": "params do not match the template",
+            "details": {
+                "missing": missing,
+                "unexpected": unexpected,
+            },
+        }),
+        PersistenceError::InvalidParamValue { name, message } => json!({
+            "code": "invalid_argument",
+            "message": message,
+            "details": {
+                "name": name,
+            },
+        }),
+        PersistenceError::InvalidPolicy(errors) => json!({
+            "code": "invalid_argument",
+            "message": "policy has invalid templates",
+            "details": {
+                "errors": errors,
+            },
+        }),
+        PersistenceError::NotAcceptable { accept, encoding } => json!({
+            "code": "not_acceptable",
+            "message": "rows can't be written in any of the accepted formats",
+            "details": {
+                "accept": accept,
+                "available": RowFormat::ALL
+                    .iter()
+                    .filter(|format| format.can_encode(encoding))
+                    .map(|format| format.content_type())
+                    .collect::<Vec<_>>(),
+            },
+        }),
+        PersistenceError::TooManyRows(max_rows) => json!({
+            "code": "too_many_rows",
+            "message": "query returned too many rows, page through them with `limit`",
+            "details": {
+                "maxRows": max_rows,
+            },
+        }),
+        PersistenceError::VersionMismatch { expected, actual } => json!({
+            "code": "failed_precondition",
+            "message": "the policy has changed since that version",
+            "details": {
+                "expectedVersion": expected,
+                "currentVersion": actual,
+            },
+        }),
+        PersistenceError::MigrationConflict { name, message } => json!({
+            "code": "migration_conflict",
+            "message": format!("migration {} {}", name, message),
+            "details": {
+                "name": name,
+            },
+        }),
+        PersistenceError::DeadlineExceeded => json!({
+            "code": "deadline_exceeded",
+            "message": "Statement took too long and was aborted",
+        }),
+        PersistenceError::Interrupted => json!({
+            "code": "interrupted",
+            "message": "Operation was interrupted",
+        }),
+        PersistenceError::Busy => {
+            response.set_header(header::RETRY_AFTER, BUSY_RETRY_AFTER_SECS.to_string());
+            json!({
+                "code": "busy",
+                "message": "Database is busy, back off and try again",
+            })
+        }
+        PersistenceError::BatchFailed { step, cause } => {
+            let mut payload = error_payload(*cause, response);
+            payload["details"]["step"] = json!(step);
+            payload
+        }
+    }
+}
+
+// How long clients should wait before retrying a request that failed with `Busy`.
+const BUSY_RETRY_AFTER_SECS: u64 = 1;
+
+fn status_code(e: &PersistenceError) -> StatusCode {
+    match e {
+        PersistenceError::Unknown(_) | PersistenceError::Interrupted => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        PersistenceError::NoSuchQuery(_)
+        | PersistenceError::NoSuchKey(_)
+        | PersistenceError::NoSuchJob(_)
+        | PersistenceError::NoSuchRevision(_) => StatusCode::NOT_FOUND,
+        PersistenceError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+        PersistenceError::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+        PersistenceError::InvalidArgument(_)
+        | PersistenceError::InvalidParams { .. }
+        | PersistenceError::InvalidParamValue { .. }
+        | PersistenceError::InvalidPolicy(_)
+        | PersistenceError::SyntaxError(_)
+        | PersistenceError::SchemaError(_)
+        | PersistenceError::TooManyRows(_) => StatusCode::BAD_REQUEST,
+        PersistenceError::ConstraintViolation(_) | PersistenceError::MigrationConflict { .. } => {
+            StatusCode::CONFLICT
         }
+        PersistenceError::NotAcceptable { .. } => StatusCode::NOT_ACCEPTABLE,
+        PersistenceError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+        PersistenceError::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        PersistenceError::VersionMismatch { .. } => StatusCode::PRECONDITION_FAILED,
+        PersistenceError::BatchFailed { cause, .. } => status_code(cause),
     }
 }
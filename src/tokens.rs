@@ -1,7 +1,7 @@
 use serde::{Deserialize, Deserializer};
 use std::{fmt, str::FromStr};
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct ProjectId(String);
 impl FromStr for ProjectId {
     type Err = String;
@@ -29,7 +29,7 @@ impl<'de> Deserialize<'de> for ProjectId {
     }
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct DatabaseId(String);
 impl FromStr for DatabaseId {
     type Err = String;
@@ -70,7 +70,7 @@ fn is_valid_token(raw: &str) -> bool {
             .all(|(idx, b)| b.is_ascii_alphabetic() || (idx > 0 && b.is_ascii_digit()))
 }
 
-#[derive(Hash, PartialEq, Eq)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct DatabaseAddress {
     pub project_id: ProjectId,
     pub database_id: DatabaseId,
@@ -80,3 +80,8 @@ impl DatabaseAddress {
         format!("{}-{}.sqlite", self.project_id, self.database_id)
     }
 }
+impl fmt::Display for DatabaseAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.project_id, self.database_id)
+    }
+}
@@ -0,0 +1,154 @@
+//! A lossless JSON encoding for the values SQLite stores.
+//!
+//! Plain JSON can't express a blob, JavaScript rounds integers beyond 2^53, and infinite
+//! floats have no JSON representation at all. The typed encoding tags those values with
+//! a single-key object: `{"$blob": "<base64>"}`, `{"$int": "<decimal>"}` and
+//! `{"$real": "Infinity"}`.
+//!
+//! Persistence always binds params in the typed encoding, and the server converts plain
+//! ones to it. Rows are written straight from SQLite in whichever encoding the request
+//! asked for with `Ezdb-Value-Encoding`.
+
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// The request header that picks a request's value encoding.
+pub const HEADER: &str = "ezdb-value-encoding";
+
+const BLOB_TAG: &str = "$blob";
+const INT_TAG: &str = "$int";
+const REAL_TAG: &str = "$real";
+
+// The largest integer that a JavaScript number holds exactly.
+const MAX_SAFE_INTEGER: i64 = (1 << 53) - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueEncoding {
+    /// Blobs are arrays of bytes, and all integers and finite floats are JSON numbers.
+    Plain,
+    /// Values that plain JSON can't express exactly are tagged.
+    Typed,
+}
+
+impl std::str::FromStr for ValueEncoding {
+    type Err = String;
+    fn from_str(s: &str) -> Result<ValueEncoding, String> {
+        match s {
+            "plain" => Ok(ValueEncoding::Plain),
+            "typed" => Ok(ValueEncoding::Typed),
+            _ => Err(format!("unknown value encoding `{}`", s)),
+        }
+    }
+}
+
+/// A value that a tagged object stands for.
+#[derive(Debug, PartialEq)]
+pub enum Tagged {
+    Blob(Vec<u8>),
+    Integer(i64),
+    Real(f64),
+}
+
+/// Decodes `value` if it's a tagged object, or returns `None` if it isn't one.
+pub fn decode(value: &Value) -> Option<Result<Tagged, String>> {
+    let (tag, inner) = match value {
+        Value::Object(map) if map.len() == 1 => map.iter().next().unwrap(),
+        _ => return None,
+    };
+    let text = match (tag.as_str(), inner) {
+        (BLOB_TAG, Value::String(s))
+        | (INT_TAG, Value::String(s))
+        | (REAL_TAG, Value::String(s)) => s,
+        (BLOB_TAG, _) | (INT_TAG, _) | (REAL_TAG, _) => {
+            return Some(Err(format!("{} must be a string", tag)))
+        }
+        _ => return None,
+    };
+    Some(match tag.as_str() {
+        BLOB_TAG => base64::decode(text)
+            .map(Tagged::Blob)
+            .map_err(|e| format!("invalid {}: {}", tag, e)),
+        INT_TAG => text
+            .parse()
+            .map(Tagged::Integer)
+            .map_err(|e| format!("invalid {}: {}", tag, e)),
+        _ => match text.as_str() {
+            "Infinity" => Ok(Tagged::Real(f64::INFINITY)),
+            "-Infinity" => Ok(Tagged::Real(f64::NEG_INFINITY)),
+            _ => text
+                .parse()
+                .map(Tagged::Real)
+                .map_err(|e| format!("invalid {}: {}", tag, e)),
+        },
+    })
+}
+
+pub fn blob(bytes: &[u8]) -> Value {
+    json!({ BLOB_TAG: base64::encode(bytes) })
+}
+
+/// Integers that JavaScript can't hold exactly are tagged, and the rest are numbers.
+pub fn integer(i: i64) -> Value {
+    if (-MAX_SAFE_INTEGER..=MAX_SAFE_INTEGER).contains(&i) {
+        json!(i)
+    } else {
+        json!({ INT_TAG: i.to_string() })
+    }
+}
+
+/// Infinite floats are tagged, and the rest are numbers.
+pub fn real(f: f64) -> Value {
+    if f.is_finite() {
+        json!(f)
+    } else if f > 0.0 {
+        json!({ REAL_TAG: "Infinity" })
+    } else {
+        json!({ REAL_TAG: "-Infinity" })
+    }
+}
+
+/// Makes the params of a plain request safe to bind as typed ones. Objects are bound as
+/// JSON text either way, so turning them into text first keeps any that happen to look
+/// like tags from being decoded.
+pub fn params_from_plain(params: BTreeMap<String, Value>) -> BTreeMap<String, Value> {
+    params
+        .into_iter()
+        .map(|(name, value)| match value {
+            Value::Object(map) => (name, Value::String(Value::Object(map).to_string())),
+            value => (name, value),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, integer, params_from_plain, real, Tagged};
+    use serde_json::json;
+
+    #[test]
+    fn tagged_values_round_trip() {
+        assert_eq!(integer(42), json!(42));
+        assert_eq!(integer(i64::MAX), json!({"$int": "9223372036854775807"}));
+        assert_eq!(
+            decode(&integer(i64::MIN)),
+            Some(Ok(Tagged::Integer(i64::MIN)))
+        );
+        assert_eq!(real(f64::NEG_INFINITY), json!({"$real": "-Infinity"}));
+        assert_eq!(
+            decode(&json!({"$blob": "AQI="})),
+            Some(Ok(Tagged::Blob(vec![1, 2])))
+        );
+        assert_eq!(decode(&json!({"$blob": "AQI=", "x": 1})), None);
+        assert!(matches!(decode(&json!({"$int": 7})), Some(Err(_))));
+    }
+
+    #[test]
+    fn plain_params_are_not_decoded() {
+        let params = params_from_plain(
+            vec![(":x".to_owned(), json!({"$blob": "AQI="}))]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(params[":x"], json!(r#"{"$blob":"AQI="}"#));
+    }
+}